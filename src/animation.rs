@@ -0,0 +1,459 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use color_quant::NeuQuant;
+use image::RgbaImage;
+
+/// Which colors frames are reduced to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaletteMode {
+    /// One adaptive palette for the whole animation, so colors don't flicker between frames.
+    Global,
+    /// An adaptive palette per frame. GIF only, since APNG frames share one palette.
+    Frame,
+    /// The 216 web-safe colors.
+    Web,
+    /// 256 shades of gray.
+    Gray,
+    /// No palette at all. APNG only.
+    Full,
+}
+
+impl PaletteMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "global" => Some(PaletteMode::Global),
+            "frame" => Some(PaletteMode::Frame),
+            "web" => Some(PaletteMode::Web),
+            "gray" => Some(PaletteMode::Gray),
+            "full" => Some(PaletteMode::Full),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    /// A 4x4 Bayer matrix. Noisier than Floyd-Steinberg, but the pattern stays put from frame to
+    /// frame and compresses better.
+    Ordered,
+}
+
+impl Dither {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Dither::None),
+            "floyd-steinberg" => Some(Dither::FloydSteinberg),
+            "ordered" => Some(Dither::Ordered),
+            _ => None,
+        }
+    }
+}
+
+/// Up to 256 colors.
+struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    fn new(colors: Vec<[u8; 3]>) -> Self {
+        debug_assert!(!colors.is_empty() && colors.len() <= 256);
+        Self {
+            colors,
+        }
+    }
+
+    fn web() -> Self {
+        let levels = [0u8, 51, 102, 153, 204, 255];
+        let mut colors = Vec::with_capacity(216);
+        for &r in &levels {
+            for &g in &levels {
+                for &b in &levels {
+                    colors.push([r, g, b]);
+                }
+            }
+        }
+        Self::new(colors)
+    }
+
+    fn gray() -> Self {
+        Self::new((0..=255u8).map(|v| [v, v, v]).collect())
+    }
+
+    /// An adaptive palette for the pixels of `frames`. Long animations are sampled a few frames
+    /// at a time so building it doesn't take forever.
+    fn adaptive(frames: &[RgbaImage]) -> Self {
+        let stride = (frames.len() / 16).max(1);
+        let pixels: Vec<u8> = frames.iter()
+            .step_by(stride)
+            .flat_map(|frame| frame.iter().cloned())
+            .collect();
+        let quant = NeuQuant::new(10, 256, &pixels);
+        let colors = quant.color_map_rgb()
+            .chunks(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect();
+        Self::new(colors)
+    }
+
+    /// The index of the closest color to `color`.
+    fn nearest(&self, color: [u8; 3]) -> u8 {
+        let distance = |c: &[u8; 3]| -> i32 {
+            (0..3).map(|i| (c[i] as i32 - color[i] as i32).pow(2)).sum()
+        };
+        let (index, _) = self.colors.iter()
+            .enumerate()
+            .min_by_key(|&(_, c)| distance(c))
+            .expect("Palettes aren't empty");
+        index as u8
+    }
+
+    fn rgb_bytes(&self) -> Vec<u8> {
+        self.colors.iter()
+            .flat_map(|c| c.iter().cloned())
+            .collect()
+    }
+
+    /// Map a frame to palette indices, top row first.
+    fn quantize(&self, frame: &RgbaImage, dither: Dither) -> Vec<u8> {
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        let mut indices = Vec::with_capacity(width * height);
+        // Frames tend to reuse a lot of colors, and searching the palette for each pixel is slow.
+        let mut cache = HashMap::new();
+        // Floyd-Steinberg error carried to this row and the next, with a column of padding on
+        // either side.
+        let mut error = vec![[0i32; 3]; width + 2];
+        let mut next_error = vec![[0i32; 3]; width + 2];
+
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = frame.get_pixel(x as u32, y as u32);
+                let mut color = [pixel[0] as i32, pixel[1] as i32, pixel[2] as i32];
+                match dither {
+                    Dither::None => {}
+                    Dither::FloydSteinberg => {
+                        for (c, e) in color.iter_mut().zip(&error[x + 1]) {
+                            *c += e / 16;
+                        }
+                    }
+                    Dither::Ordered => {
+                        let offset = BAYER[y % 4][x % 4] * 4 - 30;
+                        for c in color.iter_mut() {
+                            *c += offset;
+                        }
+                    }
+                }
+
+                let clamped = [clamp(color[0]), clamp(color[1]), clamp(color[2])];
+                let index = *cache.entry(clamped)
+                    .or_insert_with(|| self.nearest(clamped));
+                indices.push(index);
+
+                if dither == Dither::FloydSteinberg {
+                    let chosen = self.colors[index as usize];
+                    for channel in 0..3 {
+                        let e = clamped[channel] as i32 - chosen[channel] as i32;
+                        error[x + 2][channel] += e * 7;
+                        next_error[x][channel] += e * 3;
+                        next_error[x + 1][channel] += e * 5;
+                        next_error[x + 2][channel] += e;
+                    }
+                }
+            }
+            error = next_error;
+            next_error = vec![[0i32; 3]; width + 2];
+        }
+        indices
+    }
+}
+
+const BAYER: [[i32; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+fn clamp(c: i32) -> u8 {
+    c.max(0).min(255) as u8
+}
+
+/// The palette shared by every frame, if there is one.
+fn shared_palette(frames: &[RgbaImage], mode: PaletteMode) -> Option<Palette> {
+    match mode {
+        PaletteMode::Global => Some(Palette::adaptive(frames)),
+        PaletteMode::Web => Some(Palette::web()),
+        PaletteMode::Gray => Some(Palette::gray()),
+        PaletteMode::Frame | PaletteMode::Full => None,
+    }
+}
+
+/// Write a looping GIF. Frame delays are in hundredths of a second, so they're rounded in a way
+/// that keeps the total length right.
+pub fn write_gif(path: &Path, frames: &[RgbaImage], fps: f32, mode: PaletteMode, dither: Dither) -> Result<(), String> {
+    use gif::SetParameter;
+
+    let first = frames.first().ok_or("There are no frames")?;
+    if mode == PaletteMode::Full {
+        return Err("GIFs need a palette, use global, frame, web or gray".into());
+    }
+    if first.width() > u16::max_value() as u32 || first.height() > u16::max_value() as u32 {
+        return Err(format!("GIFs can't be larger than {0}x{0}", u16::max_value()));
+    }
+
+    let palette = shared_palette(frames, mode);
+    let global_palette = palette.as_ref().map_or_else(Vec::new, Palette::rgb_bytes);
+    // Encoded in memory, since the encoder writes the end of the file when it's dropped and
+    // panics if that fails.
+    let mut bytes = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut bytes, first.width() as u16, first.height() as u16, &global_palette)
+        .map_err(|e| e.to_string())?;
+    encoder.set(gif::Repeat::Infinite)
+        .map_err(|e| e.to_string())?;
+
+    let centiseconds = |i: usize| (i as f32 * 100.0 / fps).round() as u16;
+    for (i, frame) in frames.iter().enumerate() {
+        let mut gif_frame = gif::Frame::default();
+        gif_frame.width = frame.width() as u16;
+        gif_frame.height = frame.height() as u16;
+        gif_frame.delay = centiseconds(i + 1) - centiseconds(i);
+        let indices = match &palette {
+            Some(palette) => palette.quantize(frame, dither),
+            None => {
+                let frame_palette = Palette::adaptive(std::slice::from_ref(frame));
+                gif_frame.palette = Some(frame_palette.rgb_bytes());
+                frame_palette.quantize(frame, dither)
+            }
+        };
+        gif_frame.buffer = Cow::Owned(indices);
+        encoder.write_frame(&gif_frame)
+            .map_err(|e| e.to_string())?;
+    }
+    drop(encoder);
+    fs::write(path, &bytes)
+        .map_err(|e| e.to_string())
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Write a looping APNG. The png crate can't write animations, so the chunks are written here.
+/// Frames are either indexed with a shared palette or full RGB.
+pub fn write_apng(path: &Path, frames: &[RgbaImage], fps: f32, mode: PaletteMode, dither: Dither) -> Result<(), String> {
+    let first = frames.first().ok_or("There are no frames")?;
+    if mode == PaletteMode::Frame {
+        return Err("APNG frames share one palette, use global, web, gray or full".into());
+    }
+    let palette = shared_palette(frames, mode);
+    let (width, height) = first.dimensions();
+
+    let file = File::create(path)
+        .map_err(|e| e.to_string())?;
+    let mut w = BufWriter::new(file);
+    let write = |w: &mut BufWriter<File>| -> io::Result<()> {
+        w.write_all(&PNG_SIGNATURE)?;
+
+        let mut header = Vec::new();
+        header.extend(&width.to_be_bytes());
+        header.extend(&height.to_be_bytes());
+        // Bit depth, then color type: indexed or RGB.
+        header.extend(&[8, if palette.is_some() { 3 } else { 2 }]);
+        // Compression, filter and interlace methods.
+        header.extend(&[0, 0, 0]);
+        write_chunk(w, b"IHDR", &header)?;
+
+        let mut animation_control = Vec::new();
+        animation_control.extend(&(frames.len() as u32).to_be_bytes());
+        // Loop forever.
+        animation_control.extend(&0u32.to_be_bytes());
+        write_chunk(w, b"acTL", &animation_control)?;
+
+        if let Some(palette) = &palette {
+            write_chunk(w, b"PLTE", &palette.rgb_bytes())?;
+        }
+
+        // fcTL and fdAT chunks share a sequence.
+        let mut sequence = 0u32;
+        // Delays are fractions of a second, so frames land exactly on 1 / fps.
+        let delay_den = (fps * 100.0).round().max(1.0).min(u16::max_value() as f32) as u16;
+        for (i, frame) in frames.iter().enumerate() {
+            let mut frame_control = Vec::new();
+            frame_control.extend(&sequence.to_be_bytes());
+            frame_control.extend(&width.to_be_bytes());
+            frame_control.extend(&height.to_be_bytes());
+            // x and y offsets.
+            frame_control.extend(&0u32.to_be_bytes());
+            frame_control.extend(&0u32.to_be_bytes());
+            frame_control.extend(&100u16.to_be_bytes());
+            frame_control.extend(&delay_den.to_be_bytes());
+            // Don't dispose, and replace the previous frame's pixels.
+            frame_control.extend(&[0, 0]);
+            write_chunk(w, b"fcTL", &frame_control)?;
+            sequence += 1;
+
+            // Every row starts with its filter type, none.
+            let row_len = match &palette {
+                Some(_) => width as usize,
+                None => width as usize * 3,
+            };
+            let pixels = match &palette {
+                Some(palette) => palette.quantize(frame, dither),
+                None => frame.pixels()
+                    .flat_map(|p| p.data[..3].to_vec())
+                    .collect(),
+            };
+            let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+            for row in pixels.chunks(row_len) {
+                raw.push(0);
+                raw.extend(row);
+            }
+            let compressed = deflate::deflate_bytes_zlib(&raw);
+
+            // The first frame doubles as the still image for viewers without APNG support.
+            if i == 0 {
+                write_chunk(w, b"IDAT", &compressed)?;
+            } else {
+                let mut frame_data = Vec::with_capacity(compressed.len() + 4);
+                frame_data.extend(&sequence.to_be_bytes());
+                frame_data.extend(&compressed);
+                write_chunk(w, b"fdAT", &frame_data)?;
+                sequence += 1;
+            }
+        }
+
+        write_chunk(w, b"IEND", &[])?;
+        w.flush()
+    };
+    write(&mut w)
+        .map_err(|e| e.to_string())
+}
+
+fn write_chunk<W>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()>
+    where W: Write {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc32(kind.iter().chain(data)).to_be_bytes())
+}
+
+/// The CRC-32 PNG chunks end with.
+fn crc32<'a, I>(bytes: I) -> u32
+    where I: IntoIterator<Item = &'a u8> {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn solid(width: u32, height: u32, color: [u8; 3]) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba { data: [color[0], color[1], color[2], 255] })
+    }
+
+    #[test]
+    fn crc32_matches_png() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn quantize_keeps_palette_colors() {
+        let colors = vec![[0, 0, 0], [255, 255, 255], [255, 0, 0]];
+        let palette = Palette::new(colors.clone());
+        let mut frame = RgbaImage::new(3, 1);
+        for (x, c) in colors.iter().enumerate() {
+            frame.put_pixel(x as u32, 0, Rgba { data: [c[0], c[1], c[2], 255] });
+        }
+        for &dither in &[Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            assert_eq!(palette.quantize(&frame, dither), vec![0, 1, 2], "{:?}", dither);
+        }
+    }
+
+    #[test]
+    fn floyd_steinberg_mixes_colors() {
+        let palette = Palette::new(vec![[0, 0, 0], [255, 255, 255]]);
+        let frame = solid(16, 16, [128, 128, 128]);
+        assert!(palette.quantize(&frame, Dither::None).iter().all(|&i| i == 1));
+        let white = palette.quantize(&frame, Dither::FloydSteinberg).iter()
+            .filter(|&&i| i == 1)
+            .count();
+        assert!(white > 100 && white < 156, "{} of 256 pixels are white", white);
+    }
+
+    /// The type and data of each chunk after the signature, checking their CRCs.
+    fn chunks(bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&bytes[..8], &PNG_SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &bytes[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let kind = &rest[4..8];
+            let data = &rest[8..8 + len];
+            let crc = &rest[8 + len..12 + len];
+            assert_eq!(crc, &crc32(kind.iter().chain(data)).to_be_bytes());
+            chunks.push((String::from_utf8(kind.to_vec()).unwrap(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn write_apng_chunks() {
+        let path = std::env::temp_dir().join(format!("shade-storm-{}.png", std::process::id()));
+        let frames = vec![solid(4, 2, [255, 0, 0]), solid(4, 2, [0, 0, 255])];
+        write_apng(&path, &frames, 30.0, PaletteMode::Full, Dither::None).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let chunks = chunks(&bytes);
+        let kinds: Vec<&str> = chunks.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, vec!["IHDR", "acTL", "fcTL", "IDAT", "fcTL", "fdAT", "IEND"]);
+        // Two frames, looping forever.
+        assert_eq!(be_u32(&chunks[1].1[..4]), 2);
+        assert_eq!(be_u32(&chunks[1].1[4..8]), 0);
+        // fcTL and fdAT chunks number themselves in order.
+        assert_eq!(be_u32(&chunks[2].1), 0);
+        assert_eq!(be_u32(&chunks[4].1), 1);
+        assert_eq!(be_u32(&chunks[5].1), 2);
+
+        // Viewers without APNG support see the first frame.
+        let still = image::load_from_memory(&bytes).unwrap().to_rgba();
+        assert_eq!(still.dimensions(), frames[0].dimensions());
+        assert_eq!(still.into_raw(), frames[0].clone().into_raw());
+    }
+
+    #[test]
+    fn write_gif_is_complete() {
+        let path = std::env::temp_dir().join(format!("shade-storm-{}.gif", std::process::id()));
+        let frames = vec![solid(4, 2, [255, 0, 0]), solid(4, 2, [0, 0, 255])];
+        write_gif(&path, &frames, 30.0, PaletteMode::Web, Dither::None).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..6], b"GIF89a");
+        // The trailer.
+        assert_eq!(bytes.last(), Some(&0x3b));
+    }
+
+    #[test]
+    fn write_apng_needs_a_shared_palette() {
+        let path = std::env::temp_dir().join(format!("shade-storm-{}-frame.png", std::process::id()));
+        let frames = vec![solid(1, 1, [0, 0, 0])];
+        assert!(write_apng(&path, &frames, 30.0, PaletteMode::Frame, Dither::None).is_err());
+    }
+}
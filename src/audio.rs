@@ -0,0 +1,235 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::path::Path;
+
+use glium::{Rect, Texture2d};
+use glium::backend::Facade;
+use glium::texture::{ClientFormat, MipmapsOption, RawImage2d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerBehavior, SamplerWrapFunction, UniformValue};
+
+use crate::ShaderError;
+
+/// Width of the texture, and the number of FFT bins and waveform samples.
+const AUDIO_WIDTH: usize = 512;
+/// Samples analyzed per update. Like Web Audio's AnalyserNode, a window of twice the width gives
+/// as many frequency bins as the width.
+const FFT_SIZE: usize = AUDIO_WIDTH * 2;
+// Web Audio's default decibel range for byte frequency data.
+const MIN_DECIBELS: f32 = -100.0;
+const MAX_DECIBELS: f32 = -30.0;
+
+/// An audio file laid out like Shadertoy's music inputs: a 512x2 texture with the spectrum in
+/// row 0 and the waveform in row 1, both around the current playback position.
+///
+/// The file is decoded up front and analyzed without an audio device, so playback position is
+/// purely a function of the shader's time. The audio loops once it ends.
+pub struct Audio {
+    // Mono samples in [-1, 1].
+    samples: Vec<f32>,
+    sample_rate: u32,
+    // Playback position of the last update, in seconds.
+    position: Option<f32>,
+    texture: Texture2d,
+}
+
+impl Audio {
+    /// Decode a WAV or Ogg Vorbis file, picked by extension.
+    pub fn load<F>(display: &F, path: &Path) -> Result<Self, ShaderError>
+        where F: Facade {
+        let audio_error = |message: String| ShaderError::Audio(path.to_path_buf(), message);
+
+        let (samples, sample_rate) = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("wav") => decode_wav(path).map_err(|e| audio_error(e.to_string()))?,
+            Some("ogg") => decode_ogg(path).map_err(|e| audio_error(e.to_string()))?,
+            _ => return Err(audio_error("Only .wav and .ogg files are supported".into())),
+        };
+        if samples.is_empty() || sample_rate == 0 {
+            return Err(audio_error("The file has no samples".into()));
+        }
+
+        let raw = RawImage2d {
+            data: Cow::Owned(vec![0u8; AUDIO_WIDTH * 2]),
+            width: AUDIO_WIDTH as u32,
+            height: 2,
+            format: ClientFormat::U8,
+        };
+        let texture = Texture2d::with_format(display, raw, UncompressedFloatFormat::U8, MipmapsOption::NoMipmap)
+            .map_err(|e| audio_error(format!("Could not create audio texture: {:?}", e)))?;
+
+        Ok(Self {
+            samples,
+            sample_rate,
+            position: None,
+            texture,
+        })
+    }
+
+    /// Length of the audio, in seconds.
+    fn duration(&self) -> f32 {
+        self.samples.len() as f32 / self.sample_rate as f32
+    }
+
+    /// Playback position, in seconds, for iChannelTime.
+    pub fn position(&self) -> f32 {
+        self.position.unwrap_or(0.0)
+    }
+
+    /// Analyze the audio at `time` and upload it to the texture. Does nothing if the position
+    /// hasn't changed, like when the shader is paused.
+    pub fn update(&mut self, time: f32) {
+        let position = time.max(0.0) % self.duration();
+        if self.position == Some(position) {
+            return;
+        }
+        self.position = Some(position);
+
+        // The window ends at the current position, like an analyser fed by a playing track.
+        let end = (position * self.sample_rate as f32) as isize;
+        let window: Vec<f32> = (end - FFT_SIZE as isize..end)
+            .map(|i| {
+                let len = self.samples.len() as isize;
+                self.samples[(((i % len) + len) % len) as usize]
+            })
+            .collect();
+
+        let mut data = Vec::with_capacity(AUDIO_WIDTH * 2);
+        data.extend(spectrum(&window).iter().map(|&magnitude| {
+            let decibels = 20.0 * magnitude.max(1e-10).log10();
+            let scaled = (decibels - MIN_DECIBELS) / (MAX_DECIBELS - MIN_DECIBELS);
+            (scaled.max(0.0).min(1.0) * 255.0) as u8
+        }));
+        data.extend(window[FFT_SIZE - AUDIO_WIDTH..].iter().map(|&sample| {
+            ((sample * 0.5 + 0.5).max(0.0).min(1.0) * 255.0) as u8
+        }));
+
+        let raw = RawImage2d {
+            data: Cow::Owned(data),
+            width: AUDIO_WIDTH as u32,
+            height: 2,
+            format: ClientFormat::U8,
+        };
+        let rect = Rect {
+            left: 0,
+            bottom: 0,
+            width: AUDIO_WIDTH as u32,
+            height: 2,
+        };
+        self.texture.write(rect, raw);
+    }
+
+    pub fn resolution(&self) -> [f32; 3] {
+        [AUDIO_WIDTH as f32, 2.0, 1.0]
+    }
+
+    pub fn as_uniform_value(&self) -> UniformValue {
+        let sampler = SamplerBehavior {
+            wrap_function: (SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp),
+            minify_filter: MinifySamplerFilter::Linear,
+            magnify_filter: MagnifySamplerFilter::Linear,
+            ..Default::default()
+        };
+        UniformValue::Texture2d(&self.texture, Some(sampler))
+    }
+}
+
+/// Whether a channel input path looks like an audio file.
+pub fn is_audio_path(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "wav" || ext == "ogg")
+}
+
+fn decode_wav(path: &Path) -> Result<(Vec<f32>, u32), Box<dyn Error>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    Ok((downmix(&interleaved, spec.channels as usize), spec.sample_rate))
+}
+
+fn decode_ogg(path: &Path) -> Result<(Vec<f32>, u32), Box<dyn Error>> {
+    let file = File::open(path)?;
+    let mut reader = lewton::inside_ogg::OggStreamReader::new(file)?;
+    let mut interleaved = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl()? {
+        interleaved.extend(packet.iter().map(|&s| s as f32 / 32768.0));
+    }
+    let channels = reader.ident_hdr.audio_channels as usize;
+    Ok((downmix(&interleaved, channels), reader.ident_hdr.audio_sample_rate))
+}
+
+/// Average interleaved channels into one.
+fn downmix(interleaved: &[f32], channels: usize) -> Vec<f32> {
+    let channels = channels.max(1);
+    interleaved.chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Magnitudes of the first half of the spectrum of `window`, which must be FFT_SIZE long. Uses a
+/// Blackman window like Web Audio.
+fn spectrum(window: &[f32]) -> Vec<f32> {
+    let n = window.len();
+    let mut re: Vec<f32> = window.iter()
+        .enumerate()
+        .map(|(i, &sample)| {
+            let x = i as f32 / n as f32;
+            let blackman = 0.42 - 0.5 * (2.0 * PI * x).cos() + 0.08 * (4.0 * PI * x).cos();
+            sample * blackman
+        })
+        .collect();
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+    re.iter()
+        .zip(&im)
+        .take(n / 2)
+        .map(|(r, i)| (r * r + i * i).sqrt() / n as f32)
+        .collect()
+}
+
+/// In-place iterative radix-2 FFT. The length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Reorder by bit-reversed index.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
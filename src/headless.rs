@@ -0,0 +1,361 @@
+use std::ffi::CString;
+use std::io::Write;
+use std::mem;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::ptr;
+use std::rc::Rc;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use glium::{Surface, SwapBuffersError, Texture2d};
+use glium::backend::{Backend, Context};
+use glium::debug::DebugCallbackBehavior;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use image::{Rgba, RgbaImage};
+
+use crate::{FrameInputs, SCREEN_SIZE, ShaderError, ShaderOptions, Uniforms, Vertex, create_quad, date_uniform, draw_shader, load_shader, parse_arg};
+use crate::animation::{self, Dither, PaletteMode};
+use crate::keyboard::Keyboard;
+use crate::shadertoy::{Multipass, Project};
+use crate::sound::{self, SoundSettings};
+
+// GL_UNSIGNED_BYTE, the type of OSMesa's color buffer.
+const UNSIGNED_BYTE: u32 = 0x1401;
+
+/// A glium backend for an OSMesa context, which renders on the CPU so it works without a GPU or
+/// a display. Everything is drawn to textures, so the context's own color buffer is a single
+/// pixel that's never looked at.
+struct OsMesaBackend {
+    context: osmesa_sys::OSMesaContext,
+    buffer: Box<[u8; 4]>,
+}
+
+unsafe impl Backend for OsMesaBackend {
+    fn swap_buffers(&self) -> Result<(), SwapBuffersError> {
+        // There's nothing to present.
+        Ok(())
+    }
+
+    unsafe fn get_proc_address(&self, symbol: &str) -> *const c_void {
+        let symbol = CString::new(symbol)
+            .expect("GL function names don't contain NULs");
+        mem::transmute(osmesa_sys::OSMesaGetProcAddress(symbol.as_ptr()))
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        (1, 1)
+    }
+
+    fn is_current(&self) -> bool {
+        unsafe { osmesa_sys::OSMesaGetCurrentContext() == self.context }
+    }
+
+    unsafe fn make_current(&self) {
+        let made_current = osmesa_sys::OSMesaMakeCurrent(self.context, self.buffer.as_ptr() as *mut _, UNSIGNED_BYTE, 1, 1);
+        assert!(made_current != 0, "Could not make the OSMesa context current");
+    }
+}
+
+impl Drop for OsMesaBackend {
+    fn drop(&mut self) {
+        unsafe { osmesa_sys::OSMesaDestroyContext(self.context) };
+    }
+}
+
+/// Create an OpenGL 3.3 core context without a window.
+pub fn create_context() -> Result<Rc<Context>, String> {
+    osmesa_sys::OsMesa::try_loading()
+        .map_err(|e| format!("Could not load OSMesa: {}", e))?;
+
+    let attribs = [
+        osmesa_sys::OSMESA_FORMAT, osmesa_sys::OSMESA_RGBA as i32,
+        osmesa_sys::OSMESA_DEPTH_BITS, 0,
+        osmesa_sys::OSMESA_PROFILE, osmesa_sys::OSMESA_CORE_PROFILE,
+        osmesa_sys::OSMESA_CONTEXT_MAJOR_VERSION, 3,
+        osmesa_sys::OSMESA_CONTEXT_MINOR_VERSION, 3,
+        0,
+    ];
+    let context = unsafe { osmesa_sys::OSMesaCreateContextAttribs(attribs.as_ptr(), ptr::null_mut()) };
+    if context.is_null() {
+        return Err("Could not create an OpenGL 3.3 context with OSMesa".into());
+    }
+
+    let backend = OsMesaBackend {
+        context,
+        buffer: Box::new([0; 4]),
+    };
+    unsafe { Context::new::<_, ()>(backend, false, DebugCallbackBehavior::Ignore) }
+        .map_err(|e| e.to_string())
+}
+
+/// A shader drawn a frame at a time into a texture rather than a window.
+pub struct Offscreen {
+    context: Rc<Context>,
+    program: glium::Program,
+    uniforms: Uniforms,
+    multipass: Multipass,
+    // Never pressed, but Shadertoy keyboard inputs still need a texture.
+    keyboard: Keyboard,
+    vertex_buffer: glium::VertexBuffer<Vertex>,
+    index_buffer: glium::IndexBuffer<u8>,
+    target: Texture2d,
+    size: (u32, u32),
+    // The date at time 0. The date uniforms advance from it with the shader's time, so the
+    // same times always see the same dates.
+    start_date: NaiveDateTime,
+}
+
+impl Offscreen {
+    pub fn new(context: Rc<Context>, options: &ShaderOptions, size: (u32, u32), start_date: NaiveDateTime) -> Result<Self, ShaderError> {
+        let loaded = load_shader(&context, &options.vs_path, &options.fs_path, options.shadertoy, &options.overrides, size)?;
+        let (vertex_buffer, index_buffer) = create_quad(&context);
+        let target = Texture2d::empty_with_format(&context, UncompressedFloatFormat::U8U8U8U8, MipmapsOption::NoMipmap, size.0, size.1)
+            .map_err(|e| ShaderError::Draw(format!("Could not create a {}x{} render target: {:?}", size.0, size.1, e)))?;
+        Ok(Self {
+            keyboard: Keyboard::new(&context)?,
+            context,
+            program: loaded.program,
+            uniforms: loaded.uniforms,
+            multipass: loaded.multipass,
+            vertex_buffer,
+            index_buffer,
+            target,
+            size,
+            start_date,
+        })
+    }
+
+    /// Render the frame at `time`, `time_delta` seconds after the previous one. Nothing depends
+    /// on how long rendering takes, so the same times always give the same frames.
+    pub fn render(&mut self, time: f32, time_delta: f32) -> Result<RgbaImage, ShaderError> {
+        let date = self.start_date + chrono::Duration::microseconds((time as f64 * 1_000_000.0) as i64);
+        self.uniforms.update(&FrameInputs {
+            resolution: [self.size.0 as f32, self.size.1 as f32],
+            time,
+            time_delta,
+            mouse: [0.0; 4],
+            date: date_uniform(&date),
+        });
+        if let Uniforms::Shadertoy(uniforms) = &mut self.uniforms {
+            uniforms.frame_rate = if time_delta > 0.0 { 1.0 / time_delta } else { 0.0 };
+            self.keyboard.update();
+            self.multipass.update_audio(uniforms.time);
+            self.multipass.render_buffers(&self.context, &self.vertex_buffer, &self.index_buffer, uniforms, &self.keyboard)?;
+        }
+
+        let mut surface = self.target.as_surface();
+        surface.clear_color(0.0, 0.0, 0.0, 1.0);
+        draw_shader(&mut surface, &self.vertex_buffer, &self.index_buffer, &self.program, &mut self.uniforms, &self.multipass, &self.keyboard)
+            .map_err(|e| ShaderError::Draw(e.to_string()))?;
+        self.uniforms.finish_frame();
+
+        // GL rows start at the bottom. The window ignores alpha, so the image does too.
+        let rows: Vec<Vec<(u8, u8, u8, u8)>> = self.target.read();
+        let mut image = RgbaImage::new(self.size.0, self.size.1);
+        for (y, row) in rows.iter().rev().enumerate() {
+            for (x, &(r, g, b, _)) in row.iter().enumerate() {
+                image.put_pixel(x as u32, y as u32, Rgba { data: [r, g, b, 255] });
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Where exported frames go.
+enum FrameSink {
+    /// Numbered PNG files, named by replacing "{}" in the pattern with the frame number.
+    Files(String),
+    /// An encoder reading raw RGBA frames from stdin.
+    Pipe(String, Child),
+}
+
+impl FrameSink {
+    fn pipe(command: &str) -> Result<Self, ShaderError> {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| ShaderError::Output(PathBuf::from(command), e.to_string()))?;
+        Ok(FrameSink::Pipe(command.into(), child))
+    }
+
+    fn write(&mut self, index: usize, image: RgbaImage) -> Result<(), ShaderError> {
+        match self {
+            FrameSink::Files(pattern) => {
+                let path = PathBuf::from(pattern.replace("{}", &format!("{:05}", index)));
+                image.save(&path)
+                    .map_err(|e| ShaderError::Output(path, e.to_string()))
+            }
+            FrameSink::Pipe(command, child) => {
+                let stdin = child.stdin.as_mut()
+                    .expect("Encoder stdin is piped");
+                stdin.write_all(&image.into_raw())
+                    .map_err(|e| ShaderError::Output(PathBuf::from(&*command), e.to_string()))
+            }
+        }
+    }
+
+    /// Wait for the encoder, if any, to finish.
+    fn finish(self) -> Result<(), ShaderError> {
+        match self {
+            FrameSink::Files(_) => Ok(()),
+            FrameSink::Pipe(command, mut child) => {
+                // Closing stdin tells the encoder there are no more frames.
+                drop(child.stdin.take());
+                let status = child.wait()
+                    .map_err(|e| ShaderError::Output(PathBuf::from(&command), e.to_string()))?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(ShaderError::Output(PathBuf::from(&command), format!("The encoder exited with {}", status)))
+                }
+            }
+        }
+    }
+}
+
+fn size_arg(args: &clap::ArgMatches) -> (u32, u32) {
+    (
+        parse_arg(args, "width", SCREEN_SIZE.0),
+        parse_arg(args, "height", SCREEN_SIZE.1),
+    )
+}
+
+/// The date shaders see at time 0. It defaults to a fixed date rather than now, so rendering
+/// the same shader twice gives the same frames.
+fn date_arg(args: &clap::ArgMatches) -> NaiveDateTime {
+    parse_arg(args, "date", NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0))
+}
+
+fn exit_with_error<E>(e: E) -> !
+    where E: std::fmt::Display {
+    eprintln!("Error: {}", e);
+    process::exit(1);
+}
+
+/// Run the `render` subcommand, exiting with an error if anything goes wrong.
+pub fn render_command(args: &clap::ArgMatches) {
+    let options = ShaderOptions::from_args(args);
+    let size = size_arg(args);
+    let time = parse_arg(args, "time", 0.0);
+    let output = Path::new(args.value_of("output").expect("Did not get an output"));
+
+    let context = create_context().unwrap_or_else(|e| exit_with_error(e));
+    let result = Offscreen::new(context, &options, size, date_arg(args)).and_then(|mut offscreen| {
+        offscreen.render(time, 0.0)?
+            .save(output)
+            .map_err(|e| ShaderError::Output(output.to_path_buf(), e.to_string()))
+    });
+    match result {
+        Ok(()) => eprintln!("Wrote \"{}\"", output.display()),
+        Err(e) => exit_with_error(e),
+    }
+}
+
+/// The `--start`, `--end` and `--fps` arguments, exiting with an error if they don't make sense.
+fn time_range_args(args: &clap::ArgMatches, default_fps: f32) -> (f32, f32, f32) {
+    let start: f32 = parse_arg(args, "start", 0.0);
+    let end: f32 = parse_arg(args, "end", 10.0);
+    let fps: f32 = parse_arg(args, "fps", default_fps);
+    if fps <= 0.0 || end < start {
+        exit_with_error("--fps must be positive and --end can't come before --start");
+    }
+    (start, end, fps)
+}
+
+/// Render frames `1 / fps` seconds apart from `start` up to `end`, passing each one to `output`
+/// along with its index.
+fn render_range<F>(offscreen: &mut Offscreen, start: f32, end: f32, fps: f32, mut output: F) -> Result<usize, ShaderError>
+    where F: FnMut(usize, RgbaImage) -> Result<(), ShaderError> {
+    let frame_count = ((end - start) * fps).round() as usize;
+    for index in 0..frame_count {
+        // Computed from the index rather than accumulated, so there's no drift.
+        let time = start + index as f32 / fps;
+        output(index, offscreen.render(time, 1.0 / fps)?)?;
+        eprint!("\rRendered frame {}/{}", index + 1, frame_count);
+    }
+    eprintln!();
+    Ok(frame_count)
+}
+
+/// Run the `sound` subcommand, exiting with an error if anything goes wrong.
+pub fn sound_command(args: &clap::ArgMatches) {
+    let options = ShaderOptions::from_args(args);
+    let settings = SoundSettings {
+        duration: parse_arg(args, "duration", 10.0),
+        sample_rate: parse_arg(args, "sample-rate", 44100),
+    };
+    let output = Path::new(args.value_of("output").expect("Did not get an output"));
+
+    let context = create_context().unwrap_or_else(|e| exit_with_error(e));
+    let result = Project::load(&options.fs_path, &options.overrides).and_then(|project| {
+        sound::render_wav(&context, &options.vs_path, &project, &settings, output)
+    });
+    match result {
+        Ok(()) => eprintln!("Wrote \"{}\"", output.display()),
+        Err(e) => exit_with_error(e),
+    }
+}
+
+/// Run the `export` subcommand, rendering frames `1 / fps` seconds apart from the start time up
+/// to the end time. Exits with an error if anything goes wrong.
+pub fn export_command(args: &clap::ArgMatches) {
+    let options = ShaderOptions::from_args(args);
+    let size = size_arg(args);
+    let (start, end, fps) = time_range_args(args, 60.0);
+
+    let mut sink = match (args.value_of("output"), args.value_of("pipe")) {
+        // Otherwise every frame would overwrite the same file.
+        (Some(pattern), _) if !pattern.contains("{}") =>
+            exit_with_error("--output needs a {} to replace with the frame number, e.g. frames/{}.png"),
+        (Some(pattern), _) => FrameSink::Files(pattern.into()),
+        (None, Some(command)) => FrameSink::pipe(command).unwrap_or_else(|e| exit_with_error(e)),
+        (None, None) => unreachable!("clap requires a destination"),
+    };
+
+    let context = create_context().unwrap_or_else(|e| exit_with_error(e));
+    let mut offscreen = Offscreen::new(context, &options, size, date_arg(args)).unwrap_or_else(|e| exit_with_error(e));
+    let frame_count = render_range(&mut offscreen, start, end, fps, |index, image| sink.write(index, image))
+        .unwrap_or_else(|e| exit_with_error(e));
+    if let Err(e) = sink.finish() {
+        exit_with_error(e);
+    }
+    eprintln!("Exported {} frames", frame_count);
+}
+
+/// Run the `animate` subcommand, rendering the time range like `export` and writing it as a
+/// looping GIF or APNG. Exits with an error if anything goes wrong.
+pub fn animate_command(args: &clap::ArgMatches) {
+    let options = ShaderOptions::from_args(args);
+    let size = size_arg(args);
+    let (start, end, fps) = time_range_args(args, 30.0);
+    let output = Path::new(args.value_of("output").expect("Did not get an output"));
+    let is_gif = output.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("gif"));
+    let palette = PaletteMode::parse(args.value_of("palette").unwrap_or("global"))
+        .unwrap_or_else(|| exit_with_error("--palette must be global, frame, web, gray or full"));
+    let dither = Dither::parse(args.value_of("dither").unwrap_or("floyd-steinberg"))
+        .unwrap_or_else(|| exit_with_error("--dither must be floyd-steinberg, ordered or none"));
+
+    let context = create_context().unwrap_or_else(|e| exit_with_error(e));
+    let mut offscreen = Offscreen::new(context, &options, size, date_arg(args)).unwrap_or_else(|e| exit_with_error(e));
+    // Global palettes are built from every frame, so they're all kept until the end.
+    let mut frames = Vec::new();
+    render_range(&mut offscreen, start, end, fps, |_, image| {
+        frames.push(image);
+        Ok(())
+    }).unwrap_or_else(|e| exit_with_error(e));
+
+    let result = if is_gif {
+        animation::write_gif(output, &frames, fps, palette, dither)
+    } else {
+        animation::write_apng(output, &frames, fps, palette, dither)
+    };
+    match result {
+        Ok(()) => eprintln!("Wrote {} frames to \"{}\"", frames.len(), output.display()),
+        Err(e) => exit_with_error(ShaderError::Output(output.to_path_buf(), e)),
+    }
+}
@@ -0,0 +1,148 @@
+use std::borrow::Cow;
+
+use glium::{Rect, Texture2d};
+use glium::backend::Facade;
+use glium::texture::{ClientFormat, MipmapsOption, RawImage2d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerBehavior, SamplerWrapFunction, UniformValue};
+use midgar::KeyCode;
+
+use crate::ShaderError;
+
+const KEY_COUNT: usize = 256;
+
+/// Keyboard state laid out like Shadertoy's keyboard input: a 256x3 texture indexed by
+/// JavaScript key code, with rows for keys held down, keys pressed this frame and keys toggled.
+pub struct Keyboard {
+    held: [bool; KEY_COUNT],
+    pressed: [bool; KEY_COUNT],
+    toggled: [bool; KEY_COUNT],
+    texture: Texture2d,
+}
+
+impl Keyboard {
+    pub fn new<F>(display: &F) -> Result<Self, ShaderError>
+        where F: Facade {
+        let raw = RawImage2d {
+            data: Cow::Owned(vec![0u8; KEY_COUNT * 3]),
+            width: KEY_COUNT as u32,
+            height: 3,
+            format: ClientFormat::U8,
+        };
+        let texture = Texture2d::with_format(display, raw, UncompressedFloatFormat::U8, MipmapsOption::NoMipmap)
+            .map_err(|e| ShaderError::Draw(format!("Could not create keyboard texture: {:?}", e)))?;
+        Ok(Self {
+            held: [false; KEY_COUNT],
+            pressed: [false; KEY_COUNT],
+            toggled: [false; KEY_COUNT],
+            texture,
+        })
+    }
+
+    pub fn key_down(&mut self, keycode: KeyCode, repeat: bool) {
+        if let Some(key) = js_key_code(keycode) {
+            if !repeat {
+                self.pressed[key] = true;
+                self.toggled[key] = !self.toggled[key];
+            }
+            self.held[key] = true;
+        }
+    }
+
+    pub fn key_up(&mut self, keycode: KeyCode) {
+        if let Some(key) = js_key_code(keycode) {
+            self.held[key] = false;
+        }
+    }
+
+    /// Upload this frame's state to the texture. Keys only count as pressed for one frame.
+    pub fn update(&mut self) {
+        let mut data = Vec::with_capacity(KEY_COUNT * 3);
+        for row in &[&self.held, &self.pressed, &self.toggled] {
+            data.extend(row.iter().map(|&down| if down { 255u8 } else { 0 }));
+        }
+        let raw = RawImage2d {
+            data: Cow::Owned(data),
+            width: KEY_COUNT as u32,
+            height: 3,
+            format: ClientFormat::U8,
+        };
+        let rect = Rect {
+            left: 0,
+            bottom: 0,
+            width: KEY_COUNT as u32,
+            height: 3,
+        };
+        self.texture.write(rect, raw);
+
+        self.pressed = [false; KEY_COUNT];
+    }
+
+    pub fn resolution(&self) -> [f32; 3] {
+        [KEY_COUNT as f32, 3.0, 1.0]
+    }
+
+    pub fn as_uniform_value(&self) -> UniformValue {
+        let sampler = SamplerBehavior {
+            wrap_function: (SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp),
+            minify_filter: MinifySamplerFilter::Nearest,
+            magnify_filter: MagnifySamplerFilter::Nearest,
+            ..Default::default()
+        };
+        UniformValue::Texture2d(&self.texture, Some(sampler))
+    }
+}
+
+/// Map an SDL keycode to the JavaScript key code Shadertoy shaders expect.
+fn js_key_code(keycode: KeyCode) -> Option<usize> {
+    use KeyCode::*;
+
+    let code = match keycode {
+        Backspace => 8,
+        Tab => 9,
+        Return | KpEnter => 13,
+        LShift | RShift => 16,
+        LCtrl | RCtrl => 17,
+        LAlt | RAlt => 18,
+        Pause => 19,
+        CapsLock => 20,
+        Escape => 27,
+        Space => 32,
+        PageUp => 33,
+        PageDown => 34,
+        End => 35,
+        Home => 36,
+        Left => 37,
+        Up => 38,
+        Right => 39,
+        Down => 40,
+        Insert => 45,
+        Delete => 46,
+        Num0 => 48, Num1 => 49, Num2 => 50, Num3 => 51, Num4 => 52,
+        Num5 => 53, Num6 => 54, Num7 => 55, Num8 => 56, Num9 => 57,
+        A => 65, B => 66, C => 67, D => 68, E => 69, F => 70, G => 71, H => 72, I => 73,
+        J => 74, K => 75, L => 76, M => 77, N => 78, O => 79, P => 80, Q => 81, R => 82,
+        S => 83, T => 84, U => 85, V => 86, W => 87, X => 88, Y => 89, Z => 90,
+        Kp0 => 96, Kp1 => 97, Kp2 => 98, Kp3 => 99, Kp4 => 100,
+        Kp5 => 101, Kp6 => 102, Kp7 => 103, Kp8 => 104, Kp9 => 105,
+        KpMultiply => 106,
+        KpPlus => 107,
+        KpMinus => 109,
+        KpPeriod => 110,
+        KpDivide => 111,
+        F1 => 112, F2 => 113, F3 => 114, F4 => 115, F5 => 116, F6 => 117,
+        F7 => 118, F8 => 119, F9 => 120, F10 => 121, F11 => 122, F12 => 123,
+        Semicolon => 186,
+        Equals => 187,
+        Comma => 188,
+        Minus => 189,
+        Period => 190,
+        Slash => 191,
+        Backquote => 192,
+        LeftBracket => 219,
+        Backslash => 220,
+        RightBracket => 221,
+        Quote => 222,
+        _ => return None,
+    };
+    Some(code)
+}
@@ -1,31 +1,54 @@
+use std::convert::TryFrom;
 use std::default::Default;
+use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{self, Receiver};
-use std::time::Duration;
+use std::process;
+use std::slice;
 
 use chrono::*;
 use fps_counter::FPSCounter;
 use glium::Program;
 use glium::backend::Facade;
+use glium::program::ProgramCreationError;
 use glium::uniforms::{AsUniformValue, Uniforms as GliumUniforms, UniformType, UniformValue};
 use imgui::*;
 use imgui_glium_renderer::Renderer as ImGuiRenderer;
 use imgui_sdl2::ImguiSdl2;
 use midgar::{Event, KeyCode, Midgar, MouseButton, Surface};
-use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use toml::Value as TomlValue;
 
+mod animation;
+mod audio;
+mod headless;
+mod keyboard;
+mod shadertoy;
+mod sound;
+mod source;
+mod texture;
+mod watch;
+mod widget;
+
+use crate::keyboard::Keyboard;
+use crate::shadertoy::{ChannelInput, Multipass, Overrides, Project};
+use crate::source::{Diagnostic, ShaderSource};
+use crate::texture::{Filter, ImageTexture, TextureOptions, Wrap};
+use crate::watch::FileSet;
+use crate::widget::{Widget, WidgetKind};
+
 const SCREEN_SIZE: (u32, u32) = (1024, 768);
 
 #[derive(Clone, Copy)]
-struct Vertex {
+pub struct Vertex {
     vertex: [f32; 2],
 }
 glium::implement_vertex!(Vertex, vertex);
 
-#[derive(Clone, Debug)]
-enum StormUniform {
+#[derive(Clone, Debug, PartialEq)]
+pub enum StormUniform {
     // User set types.
     Float(f32),
     FloatVec2([f32; 2]),
@@ -55,11 +78,65 @@ enum StormUniform {
     BoolVec2([bool; 2]),
     BoolVec3([bool; 3]),
     BoolVec4([bool; 4]),
+    // Matrices are stored column by column.
+    FloatMat2([[f32; 2]; 2]),
+    FloatMat3([[f32; 3]; 3]),
+    FloatMat4([[f32; 4]; 4]),
 
     // Standard types.
     ColorRgb([f32; 3]),
     ColorRgba([f32; 4]),
     Resolution([f32; 2]),
+    // Width over height of the viewport.
+    Aspect(f32),
+    Time(f32),
+    TimeDelta(f32),
+    Frame(i32),
+    // Laid out like Shadertoy's iMouse, in pixels or normalized by the resolution.
+    Mouse([f32; 4]),
+    MouseNormalized([f32; 4]),
+    // Laid out like Shadertoy's iDate.
+    Date([f32; 4]),
+}
+
+impl StormUniform {
+    /// The GLSL type of the uniform, for error messages.
+    fn type_name(&self) -> &'static str {
+        use StormUniform::*;
+        match self {
+            Float(_) | Aspect(_) | Time(_) | TimeDelta(_) => "float",
+            FloatVec2(_) | Resolution(_) => "vec2",
+            FloatVec3(_) | ColorRgb(_) => "vec3",
+            FloatVec4(_) | ColorRgba(_) | Mouse(_) | MouseNormalized(_) | Date(_) => "vec4",
+            Double(_) => "double",
+            DoubleVec2(_) => "dvec2",
+            DoubleVec3(_) => "dvec3",
+            DoubleVec4(_) => "dvec4",
+            Int(_) | Frame(_) => "int",
+            IntVec2(_) => "ivec2",
+            IntVec3(_) => "ivec3",
+            IntVec4(_) => "ivec4",
+            UnsignedInt(_) => "uint",
+            UnsignedIntVec2(_) => "uvec2",
+            UnsignedIntVec3(_) => "uvec3",
+            UnsignedIntVec4(_) => "uvec4",
+            Int64(_) => "int64_t",
+            Int64Vec2(_) => "i64vec2",
+            Int64Vec3(_) => "i64vec3",
+            Int64Vec4(_) => "i64vec4",
+            UnsignedInt64(_) => "uint64_t",
+            UnsignedInt64Vec2(_) => "u64vec2",
+            UnsignedInt64Vec3(_) => "u64vec3",
+            UnsignedInt64Vec4(_) => "u64vec4",
+            Bool(_) => "bool",
+            BoolVec2(_) => "bvec2",
+            BoolVec3(_) => "bvec3",
+            BoolVec4(_) => "bvec4",
+            FloatMat2(_) => "mat2",
+            FloatMat3(_) => "mat3",
+            FloatMat4(_) => "mat4",
+        }
+    }
 }
 
 impl AsUniformValue for StormUniform {
@@ -94,9 +171,15 @@ impl AsUniformValue for StormUniform {
             BoolVec2(v) => v.as_uniform_value(),
             BoolVec3(v) => v.as_uniform_value(),
             BoolVec4(v) => v.as_uniform_value(),
+            FloatMat2(m) => m.as_uniform_value(),
+            FloatMat3(m) => m.as_uniform_value(),
+            FloatMat4(m) => m.as_uniform_value(),
             ColorRgb(v) => v.as_uniform_value(),
             ColorRgba(v) => v.as_uniform_value(),
             Resolution(v) => v.as_uniform_value(),
+            Aspect(v) | Time(v) | TimeDelta(v) => v.as_uniform_value(),
+            Frame(v) => v.as_uniform_value(),
+            Mouse(v) | MouseNormalized(v) | Date(v) => v.as_uniform_value(),
         }
     }
 }
@@ -105,25 +188,271 @@ impl AsUniformValue for StormUniform {
 struct UniformHolder {
     name: String,
     value: StormUniform,
+    // The value from the TOML block, or the type's default if it isn't set there.
+    default: StormUniform,
+    widget: Widget,
 }
 
 impl UniformHolder {
     fn new(name: String, value: StormUniform) -> Self {
         Self {
             name,
+            default: value.clone(),
             value,
+            widget: Widget::default(),
+        }
+    }
+
+    /// Apply the uniform's entry in a freeform shader's TOML block, either a bare value or an
+    /// inline table describing its widget.
+    fn apply_toml(&mut self, value: &TomlValue) -> Result<(), String> {
+        let value = match value {
+            TomlValue::Table(table) => {
+                let (widget, default) = Widget::parse(table)?;
+                widget.check(&self.value)?;
+                if widget.kind == Some(WidgetKind::Color) {
+                    self.make_color()?;
+                }
+                self.widget = widget;
+                match default {
+                    Some(default) => default,
+                    None => {
+                        self.widget.clamp_value(&mut self.value);
+                        return Ok(());
+                    }
+                }
+            }
+            value => value.clone(),
+        };
+
+        let result = match &value {
+            TomlValue::String(s) if s == "color" => self.make_color(),
+            TomlValue::String(s) => self.make_semantic(s),
+            value => self.set_value(value),
+        };
+        // Widgets only clamp values that are edited, so a default outside their range is clamped
+        // here. Otherwise the first frame would clamp it and it would look like a tweak.
+        self.widget.clamp_value(&mut self.value);
+        result
+    }
+
+    /// Tie the uniform to a value that's filled in every frame, like the window's resolution or
+    /// the time.
+    fn make_semantic(&mut self, semantic: &str) -> Result<(), String> {
+        use StormUniform::*;
+
+        let value = match semantic {
+            "resolution" => Resolution([0.0; 2]),
+            "aspect" => Aspect(0.0),
+            "time" => Time(0.0),
+            "time_delta" => TimeDelta(0.0),
+            "frame" => Frame(0),
+            "mouse" => Mouse([0.0; 4]),
+            "mouse_normalized" => MouseNormalized([0.0; 4]),
+            "date" => Date([0.0; 4]),
+            _ => return Err(format!("Unknown setting \"{}\"", semantic)),
+        };
+        if value.type_name() != self.value.type_name() {
+            return Err(format!("\"{}\" needs a {} uniform, not a {}", semantic, value.type_name(), self.value.type_name()));
+        }
+        self.value = value;
+        Ok(())
+    }
+
+    /// The array or struct this uniform is part of, if any: "weights" for "weights[2]" and "light"
+    /// for "light.color".
+    fn parent(&self) -> Option<&str> {
+        self.name.find(|c| c == '[' || c == '.')
+            .map(|i| &self.name[..i])
+    }
+
+    fn make_color(&mut self) -> Result<(), String> {
+        self.value = match self.value {
+            StormUniform::FloatVec3(_) => StormUniform::ColorRgb([1.0; 3]),
+            StormUniform::FloatVec4(_) => StormUniform::ColorRgba([1.0; 4]),
+            StormUniform::ColorRgb(_) | StormUniform::ColorRgba(_) => return Ok(()),
+            _ => return Err(format!("colors need a vec3 or vec4 uniform, not a {}", self.value.type_name())),
+        };
+        Ok(())
+    }
+
+    /// Set the uniform's value from a TOML number, boolean or array. Arrays set vectors and must
+    /// have one element per component.
+    fn set_value(&mut self, value: &TomlValue) -> Result<(), String> {
+        use StormUniform::*;
+
+        let fits = match &mut self.value {
+            Float(f) => set_components(slice::from_mut(f), value, toml_float),
+            FloatVec2(v) => set_components(v, value, toml_float),
+            FloatVec3(v) | ColorRgb(v) => set_components(v, value, toml_float),
+            FloatVec4(v) | ColorRgba(v) => set_components(v, value, toml_float),
+            Double(d) => set_components(slice::from_mut(d), value, toml_double),
+            DoubleVec2(v) => set_components(v, value, toml_double),
+            DoubleVec3(v) => set_components(v, value, toml_double),
+            DoubleVec4(v) => set_components(v, value, toml_double),
+            Int(i) => set_components(slice::from_mut(i), value, toml_integer),
+            IntVec2(v) => set_components(v, value, toml_integer),
+            IntVec3(v) => set_components(v, value, toml_integer),
+            IntVec4(v) => set_components(v, value, toml_integer),
+            UnsignedInt(u) => set_components(slice::from_mut(u), value, toml_integer),
+            UnsignedIntVec2(v) => set_components(v, value, toml_integer),
+            UnsignedIntVec3(v) => set_components(v, value, toml_integer),
+            UnsignedIntVec4(v) => set_components(v, value, toml_integer),
+            Int64(i) => set_components(slice::from_mut(i), value, toml_integer),
+            Int64Vec2(v) => set_components(v, value, toml_integer),
+            Int64Vec3(v) => set_components(v, value, toml_integer),
+            Int64Vec4(v) => set_components(v, value, toml_integer),
+            UnsignedInt64(u) => set_components(slice::from_mut(u), value, toml_integer),
+            UnsignedInt64Vec2(v) => set_components(v, value, toml_integer),
+            UnsignedInt64Vec3(v) => set_components(v, value, toml_integer),
+            UnsignedInt64Vec4(v) => set_components(v, value, toml_integer),
+            Bool(b) => set_components(slice::from_mut(b), value, TomlValue::as_bool),
+            BoolVec2(v) => set_components(v, value, TomlValue::as_bool),
+            BoolVec3(v) => set_components(v, value, TomlValue::as_bool),
+            BoolVec4(v) => set_components(v, value, TomlValue::as_bool),
+            // Matrices are written as an array of columns.
+            FloatMat2(m) => set_components(m, value, toml_column),
+            FloatMat3(m) => set_components(m, value, toml_column),
+            FloatMat4(m) => set_components(m, value, toml_column),
+            // Set every frame.
+            Resolution(_) | Aspect(_) | Time(_) | TimeDelta(_) | Frame(_) | Mouse(_)
+            | MouseNormalized(_) | Date(_) => false,
+        };
+
+        if fits {
+            Ok(())
+        } else {
+            Err(format!("{} {} doesn't fit a {} uniform", value.type_str(), value, self.value.type_name()))
         }
     }
 }
 
+/// Convert a TOML value into a scalar or vector's components, leaving them untouched unless all
+/// of them convert. Returns whether they did.
+fn set_components<T, F>(components: &mut [T], value: &TomlValue, convert: F) -> bool
+    where T: Copy, F: Fn(&TomlValue) -> Option<T> {
+    let converted: Option<Vec<T>> = match value {
+        TomlValue::Array(array) if array.len() == components.len() => array.iter()
+            .map(&convert)
+            .collect(),
+        TomlValue::Array(_) => None,
+        value if components.len() == 1 => convert(value).map(|c| vec![c]),
+        _ => None,
+    };
+    match converted {
+        Some(converted) => {
+            components.copy_from_slice(&converted);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Floats can be written as integers too.
+fn toml_double(value: &TomlValue) -> Option<f64> {
+    match value {
+        TomlValue::Float(f) => Some(*f),
+        TomlValue::Integer(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+fn toml_float(value: &TomlValue) -> Option<f32> {
+    toml_double(value).map(|f| f as f32)
+}
+
+fn toml_column<C>(value: &TomlValue) -> Option<C>
+    where C: Default + AsMut<[f32]> {
+    let mut column = C::default();
+    if set_components(column.as_mut(), value, toml_float) {
+        Some(column)
+    } else {
+        None
+    }
+}
+
+/// An integer that fits in `T`, so negative numbers don't end up in unsigned uniforms.
+fn toml_integer<T>(value: &TomlValue) -> Option<T>
+    where T: TryFrom<i64> {
+    value.as_integer().and_then(|i| T::try_from(i).ok())
+}
+
+/// A sampler2D uniform, bound to an image named in the TOML block.
+#[derive(Debug)]
+struct FreeformTexture {
+    name: String,
+    // Relative to the shader until the texture is loaded.
+    path: Option<PathBuf>,
+    options: TextureOptions,
+    texture: Option<ImageTexture>,
+}
+
+impl FreeformTexture {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            path: None,
+            options: TextureOptions::default(),
+            texture: None,
+        }
+    }
+
+    /// Apply the texture's entry in the TOML block, either a path or a table like
+    /// `{ path = "noise.png", filter = "nearest", wrap = "clamp", srgb = true }`.
+    fn apply_toml(&mut self, value: &TomlValue) -> Result<(), String> {
+        let table = match value {
+            TomlValue::String(path) => {
+                self.path = Some(PathBuf::from(path));
+                return Ok(());
+            }
+            TomlValue::Table(table) => table,
+            value => return Err(format!("textures need a path or a table, not {} {}", value.type_str(), value)),
+        };
+
+        for (key, value) in table {
+            match key.as_str() {
+                "path" => {
+                    let path = value.as_str()
+                        .ok_or("\"path\" must be a string")?;
+                    self.path = Some(PathBuf::from(path));
+                }
+                "filter" => {
+                    self.options.filter = value.as_str()
+                        .and_then(Filter::parse)
+                        .ok_or("\"filter\" must be nearest, linear or mipmap")?;
+                }
+                "wrap" => {
+                    self.options.wrap = value.as_str()
+                        .and_then(Wrap::parse)
+                        .ok_or("\"wrap\" must be repeat, clamp or mirror")?;
+                }
+                "srgb" => {
+                    self.options.srgb = value.as_bool()
+                        .ok_or("\"srgb\" must be true or false")?;
+                }
+                key => return Err(format!("Unknown texture setting \"{}\"", key)),
+            }
+        }
+        if self.path.is_none() {
+            return Err("texture tables need a \"path\"".into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct FreeformUniforms {
     uniforms: Vec<UniformHolder>,
+    textures: Vec<FreeformTexture>,
+    // Seconds since the shader was loaded, and frames rendered since, for "time" and "frame".
+    time: f32,
+    frame: i32,
 }
 
 impl FreeformUniforms {
     fn new(program: &Program) -> Self {
         let mut uniforms = Vec::new();
+        let mut textures = Vec::new();
         for (name, uniform) in program.uniforms() {
             use StormUniform::*;
 
@@ -157,31 +486,185 @@ impl FreeformUniforms {
                 UniformType::BoolVec2 => BoolVec2([false; 2]),
                 UniformType::BoolVec3 => BoolVec3([false; 3]),
                 UniformType::BoolVec4 => BoolVec4([false; 4]),
+                UniformType::FloatMat2 => FloatMat2([[1.0, 0.0], [0.0, 1.0]]),
+                UniformType::FloatMat3 => FloatMat3([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]),
+                UniformType::FloatMat4 => FloatMat4([[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]),
+
+                // Bound once the TOML block names an image for it.
+                UniformType::Sampler2d => {
+                    textures.push(FreeformTexture::new(name.clone()));
+                    continue;
+                }
 
-                // TODO: Return a result instead of panicking.
-                ty => panic!("Uniforms of type {:?} are unimplemented!", ty),
+                // Other samplers, non-square matrices and the like keep their GL defaults.
+                ty => {
+                    eprintln!("Uniform {} of type {:?} is not supported, leaving it unset", name, ty);
+                    continue;
+                }
             };
             eprintln!("{}: {:?}", name, uniform.ty);
             uniforms.push(UniformHolder::new(name.clone(), value));
         }
-        // TODO: Sort uniforms by name?
+        // Keep array elements and struct members together, in order.
+        uniforms.sort_by_key(|holder| sort_key(&holder.name));
         Self {
             uniforms,
+            textures,
+            time: 0.0,
+            frame: 0,
+        }
+    }
+
+    /// Apply an entry of the TOML block. Arrays and structs don't have a uniform of their own, so
+    /// they're set with an array of values for their elements or a table of values for their
+    /// members. A widget table for an array applies to each of its elements.
+    fn apply_toml(&mut self, name: &str, value: &TomlValue) -> Result<(), String> {
+        if let Some(texture) = self.textures.iter_mut().find(|t| t.name == name) {
+            return texture.apply_toml(value)
+                .map_err(|message| format!("\"{}\": {}", name, message));
+        }
+        if let Some(holder) = self.uniforms.iter_mut().find(|h| h.name == name) {
+            holder.apply_toml(value)
+                .map_err(|message| format!("\"{}\": {}", name, message))?;
+            holder.default = holder.value.clone();
+            return Ok(());
+        }
+
+        let is_array = self.uniforms.iter().any(|h| h.name.starts_with(&format!("{}[", name)));
+        let is_struct = self.uniforms.iter().any(|h| h.name.starts_with(&format!("{}.", name)));
+        match value {
+            TomlValue::Array(elements) if is_array => {
+                for (i, element) in elements.iter().enumerate() {
+                    let element_name = format!("{}[{}]", name, i);
+                    if !self.uniforms.iter().any(|h| h.name.starts_with(&element_name)) {
+                        return Err(format!("\"{}\" has fewer than {} elements", name, elements.len()));
+                    }
+                    self.apply_toml(&element_name, element)?;
+                }
+            }
+            TomlValue::Table(members) if is_struct => {
+                for (member, member_value) in members {
+                    self.apply_toml(&format!("{}.{}", name, member), member_value)?;
+                }
+            }
+            TomlValue::Table(_) if is_array => {
+                let elements: Vec<String> = self.uniforms.iter()
+                    .filter(|h| h.name.starts_with(&format!("{}[", name)))
+                    .map(|h| h.name.clone())
+                    .collect();
+                for element in elements {
+                    self.apply_toml(&element, value)?;
+                }
+            }
+            // Uniforms the shader doesn't use are optimized away, so there's nothing to set.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Carry over values tweaked in the UI from the uniforms of the previous build of the shader.
+    /// Only uniforms with the same name and type keep their value, and untweaked ones take the
+    /// new default in case it was edited.
+    fn keep_tweaks(&mut self, old: &FreeformUniforms) {
+        for holder in &mut self.uniforms {
+            let tweaked = old.uniforms.iter()
+                .find(|h| h.name == holder.name && h.value != h.default);
+            if let Some(old_holder) = tweaked {
+                if mem::discriminant(&old_holder.value) == mem::discriminant(&holder.value) {
+                    holder.value = old_holder.value.clone();
+                }
+            }
+        }
+    }
+
+    /// Set every uniform back to its value from the TOML block.
+    fn reset_to_defaults(&mut self) {
+        for holder in &mut self.uniforms {
+            holder.value = holder.default.clone();
+        }
+    }
+
+    /// Load the images named in the TOML block, with paths relative to the shader's directory.
+    fn load_textures<F>(&mut self, display: &F, base: &Path) -> Result<(), ShaderError>
+        where F: Facade {
+        for texture in &mut self.textures {
+            if let Some(path) = &mut texture.path {
+                // Canonical paths can be compared with the ones the file watcher reports.
+                let joined = base.join(&*path);
+                *path = fs::canonicalize(&joined)
+                    .map_err(|e| ShaderError::Texture(joined, e.to_string()))?;
+                texture.texture = Some(texture::load_texture(display, path, &texture.options)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Images the textures were loaded from.
+    fn texture_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.textures.iter()
+            .filter_map(|texture| texture.path.as_ref())
+    }
+
+    /// Draw widgets for every uniform. Array elements and struct members are grouped under a tree
+    /// node for their parent.
+    fn draw_widgets(&mut self, ui: &Ui) {
+        let holders = &mut self.uniforms;
+        let mut i = 0;
+        while i < holders.len() {
+            let parent = match holders[i].parent() {
+                Some(parent) => parent.to_string(),
+                None => {
+                    let holder = &mut holders[i];
+                    holder.widget.draw(ui, &holder.name, &holder.name, &mut holder.value);
+                    i += 1;
+                    continue;
+                }
+            };
+            let end = i + holders[i..].iter()
+                .take_while(|h| h.parent() == Some(parent.as_str()))
+                .count();
+            let group = &mut holders[i..end];
+            ui.tree_node(im_str!("{}", parent)).build(|| {
+                for holder in group {
+                    let member = &holder.name[parent.len()..];
+                    holder.widget.draw(ui, &holder.name, member, &mut holder.value);
+                }
+            });
+            i = end;
         }
     }
 }
 
+/// Pad array indices so "weights[10]" sorts after "weights[9]".
+fn sort_key(name: &str) -> String {
+    let mut key = String::new();
+    for (i, part) in name.split('[').enumerate() {
+        if i == 0 {
+            key.push_str(part);
+            continue;
+        }
+        let index_end = part.find(']').unwrap_or_else(|| part.len());
+        key.push_str(&format!("[{:0>10}{}", &part[..index_end], &part[index_end..]));
+    }
+    key
+}
+
 impl GliumUniforms for FreeformUniforms {
     fn visit_values<'uniform, F>(&'uniform self, mut output: F)
         where F: FnMut(&str, UniformValue<'uniform>) {
         for holder in &self.uniforms {
             output(&holder.name, holder.value.as_uniform_value());
         }
+        for texture in &self.textures {
+            if let Some(loaded) = &texture.texture {
+                output(&texture.name, loaded.as_uniform_value(texture.options.sampler()));
+            }
+        }
     }
 }
 
 #[derive(Debug)]
-struct ShadertoyUniforms {
+pub struct ShadertoyUniforms {
     // (vec3) iResolution, image, The viewport resolution (z is pixel aspect ratio, usually 1.0)
     resolution: [f32; 3],
     // (float) iTime, image/sound, Current time in seconds
@@ -193,17 +676,17 @@ struct ShadertoyUniforms {
     // (float) iFrameRate, image, Number of frames rendered per second
     frame_rate: f32,
     // (float) iChannelTime[4], image, Time for channel (if video or sound), in seconds
-    //channel_time: [f32; 4],
+    channel_time: [f32; 4],
     // (vec3) iChannelResolution[4], image/sound, Input texture resolution for each channel
-    //channel_resolution: [[f32;3]; 4],
+    channel_resolution: [[f32; 3]; 4],
     // (vec4) iMouse, image, xy = current pixel coords (if LMB is down). zw = click pixel
     mouse: [f32; 4],
     // (sampler2D), iChannel{i}, image/sound, Sampler for input textures i
-    //channel: Sampler2D,
+    // NOTE: Samplers borrow their textures, so they're added at draw time by Multipass.
     // (vec4) iDate, image/sound, Year, month, day, time in seconds in .xyzw
     date: [f32; 4],
     // (float) iSampleRate, image/sound, The sound sample rate (typically 44100)
-    //sample_rate: f32,
+    sample_rate: f32,
 }
 
 impl ShadertoyUniforms {
@@ -214,12 +697,11 @@ impl ShadertoyUniforms {
             time_delta: 0.0,
             frame: 0,
             frame_rate: 0.0,
-            //channel_time: Default::default(),
-            //channel_resolution: Default::default(),
+            channel_time: Default::default(),
+            channel_resolution: Default::default(),
             mouse: Default::default(),
-            //channel: Sampler2D,
             date: Default::default(),
-            //sample_rate: 0.0,
+            sample_rate: 44100.0,
         }
     }
 }
@@ -234,12 +716,15 @@ impl GliumUniforms for ShadertoyUniforms {
             output("iTimeDelta", self.time_delta.as_uniform_value());
             output("iFrame", self.frame.as_uniform_value());
             output("iFrameRate", self.frame_rate.as_uniform_value());
-            //output("iChannelTime", self.channel_time.as_uniform_value());
-            //output("iChannelResolution", self.channel_resolution.as_uniform_value());
+            for (i, time) in self.channel_time.iter().enumerate() {
+                output(&format!("iChannelTime[{}]", i), time.as_uniform_value());
+            }
+            for (i, resolution) in self.channel_resolution.iter().enumerate() {
+                output(&format!("iChannelResolution[{}]", i), resolution.as_uniform_value());
+            }
             output("iMouse", self.mouse.as_uniform_value());
-            //output("iChannel", self.channel.as_uniform_value());
             output("iDate", self.date.as_uniform_value());
-            //output("iSampleRate", self.sample_rate.as_uniform_value());
+            output("iSampleRate", self.sample_rate.as_uniform_value());
     }
 }
 
@@ -248,6 +733,71 @@ enum Uniforms {
     Shadertoy(ShadertoyUniforms),
 }
 
+/// What the built-in uniforms are set from for a frame.
+struct FrameInputs {
+    resolution: [f32; 2],
+    // Seconds since the shader was loaded.
+    time: f32,
+    time_delta: f32,
+    // Following Shadertoy's iMouse conventions.
+    mouse: [f32; 4],
+    date: [f32; 4],
+}
+
+impl Uniforms {
+    fn time(&self) -> f32 {
+        match self {
+            Uniforms::Freeform(uniforms) => uniforms.time,
+            Uniforms::Shadertoy(uniforms) => uniforms.time,
+        }
+    }
+
+    /// Set the built-in uniforms for the frame about to be rendered.
+    fn update(&mut self, inputs: &FrameInputs) {
+        let resolution = inputs.resolution;
+        match self {
+            Uniforms::Freeform(uniforms) => {
+                uniforms.time = inputs.time;
+                let mouse = inputs.mouse;
+                for holder in &mut uniforms.uniforms {
+                    match &mut holder.value {
+                        StormUniform::Resolution(v) => *v = resolution,
+                        StormUniform::Aspect(a) => *a = resolution[0] / resolution[1].max(1.0),
+                        StormUniform::Time(t) => *t = inputs.time,
+                        StormUniform::TimeDelta(dt) => *dt = inputs.time_delta,
+                        StormUniform::Frame(f) => *f = uniforms.frame,
+                        StormUniform::Mouse(m) => *m = mouse,
+                        StormUniform::MouseNormalized(m) => *m = [
+                            mouse[0] / resolution[0].max(1.0),
+                            mouse[1] / resolution[1].max(1.0),
+                            mouse[2] / resolution[0].max(1.0),
+                            mouse[3] / resolution[1].max(1.0),
+                        ],
+                        StormUniform::Date(d) => *d = inputs.date,
+                        _ => {}
+                    }
+                }
+            }
+            Uniforms::Shadertoy(uniforms) => {
+                uniforms.resolution = [resolution[0], resolution[1], 1.0];
+                uniforms.time = inputs.time;
+                uniforms.time_delta = inputs.time_delta;
+                uniforms.mouse = inputs.mouse;
+                uniforms.date = inputs.date;
+            }
+        }
+    }
+
+    /// Count a rendered frame. The frame uniforms count frames already rendered, so the first
+    /// frame is 0.
+    fn finish_frame(&mut self) {
+        match self {
+            Uniforms::Freeform(uniforms) => uniforms.frame += 1,
+            Uniforms::Shadertoy(uniforms) => uniforms.frame += 1,
+        }
+    }
+}
+
 impl GliumUniforms for Uniforms {
     fn visit_values<'uniform, F>(&'uniform self, output: F)
         where F: FnMut(&str, UniformValue<'uniform>) {
@@ -264,16 +814,136 @@ struct UiData {
     play: bool,
     mouse_button_held: bool,
     mouse_position: [f64; 2],
+    // Whether the left button was pressed over the shader rather than the UI and is still held.
+    shader_mouse_down: bool,
+    // The mouse as the shader sees it, following Shadertoy's iMouse conventions.
+    shader_mouse: [f32; 4],
+    // Whether ImGui wanted the mouse last frame.
+    ui_wants_mouse: bool,
+    // Whether ImGui wanted the keyboard last frame, e.g. while typing into a widget.
+    ui_wants_keyboard: bool,
     date: DateTime<Local>,
 }
 
-// TODO: Return a Result to report errors compiling the shader.
-fn compile_shader<F>(display: &F, vs_src: &str, fs_src: &str) -> Program
+#[derive(Debug)]
+pub enum ShaderError {
+    /// A shader file could not be read from disk.
+    Read(PathBuf, io::Error),
+    /// The TOML block of a freeform shader is missing or malformed, with the line in the file
+    /// where the problem is if known.
+    Toml(PathBuf, Option<u32>, String),
+    /// A Shadertoy JSON export is malformed.
+    Json(PathBuf, String),
+    /// An image used as a texture input could not be loaded.
+    Texture(PathBuf, String),
+    /// An audio file used as a channel input could not be decoded.
+    Audio(PathBuf, String),
+    /// A rendered file could not be written.
+    Output(PathBuf, String),
+    /// An #include line in a shader file could not be resolved, with the line it's on.
+    Include(PathBuf, u32, String),
+    /// The driver rejected one of the shader stages.
+    Compile(Vec<Diagnostic>),
+    /// The stages compiled but could not be linked into a program.
+    Link(String),
+    /// A pass could not be drawn, or its render target could not be created.
+    Draw(String),
+}
+
+impl ShaderError {
+    /// The individual messages that make up this error, for display in the UI.
+    fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            ShaderError::Compile(diagnostics) => diagnostics.clone(),
+            ShaderError::Json(path, message)
+            | ShaderError::Texture(path, message)
+            | ShaderError::Audio(path, message)
+            | ShaderError::Output(path, message) => vec![Diagnostic {
+                file: Some(path.clone()),
+                line: None,
+                message: message.clone(),
+            }],
+            ShaderError::Read(path, e) => vec![Diagnostic {
+                file: Some(path.clone()),
+                line: None,
+                message: e.to_string(),
+            }],
+            ShaderError::Toml(path, line, message) => vec![Diagnostic {
+                file: Some(path.clone()),
+                line: *line,
+                message: message.clone(),
+            }],
+            ShaderError::Include(path, line, message) => vec![Diagnostic {
+                file: Some(path.clone()),
+                line: Some(*line),
+                message: message.clone(),
+            }],
+            ShaderError::Draw(message) => vec![Diagnostic {
+                file: None,
+                line: None,
+                message: message.clone(),
+            }],
+            ShaderError::Link(log) => log.lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| Diagnostic {
+                    file: None,
+                    line: None,
+                    message: l.trim().into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderError::Read(path, e) =>
+                write!(f, "Could not read \"{}\": {}", path.display(), e),
+            ShaderError::Toml(path, Some(line), message) =>
+                write!(f, "Invalid TOML block in \"{}\" at line {}: {}", path.display(), line, message),
+            ShaderError::Toml(path, None, message) =>
+                write!(f, "Invalid TOML block in \"{}\": {}", path.display(), message),
+            ShaderError::Json(path, message) =>
+                write!(f, "Invalid Shadertoy JSON in \"{}\": {}", path.display(), message),
+            ShaderError::Texture(path, message) =>
+                write!(f, "Could not load texture \"{}\": {}", path.display(), message),
+            ShaderError::Audio(path, message) =>
+                write!(f, "Could not load audio \"{}\": {}", path.display(), message),
+            ShaderError::Output(path, message) =>
+                write!(f, "Could not write \"{}\": {}", path.display(), message),
+            ShaderError::Include(path, line, message) =>
+                write!(f, "Invalid #include in \"{}\" at line {}: {}", path.display(), line, message),
+            ShaderError::Compile(diagnostics) => {
+                write!(f, "Could not compile shader:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            ShaderError::Link(log) =>
+                write!(f, "Could not link shader program:\n{}", log),
+            ShaderError::Draw(message) =>
+                write!(f, "Could not draw shader: {}", message),
+        }
+    }
+}
+
+impl Error for ShaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ShaderError::Read(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn compile_shader<F>(display: &F, vs_src: &ShaderSource, fs_src: &ShaderSource) -> Result<Program, ShaderError>
     where F: Facade {
     // NOTE: By default, assume shaders output sRGB colors.
     let program_creation_input = glium::program::ProgramCreationInput::SourceCode {
-        vertex_shader: vs_src,
-        fragment_shader: fs_src,
+        vertex_shader: vs_src.text(),
+        fragment_shader: fs_src.text(),
         geometry_shader: None,
         tessellation_control_shader: None,
         tessellation_evaluation_shader: None,
@@ -281,117 +951,362 @@ fn compile_shader<F>(display: &F, vs_src: &str, fs_src: &str) -> Program
         outputs_srgb: true,
         uses_point_size: false,
     };
-    match glium::Program::new(display, program_creation_input) {
-        Ok(program) => return program,
-        Err(e) => panic!(format!("Error: Could not create shader program:\n{}", e)),
+    glium::Program::new(display, program_creation_input)
+        .map_err(|e| match e {
+            // NOTE: glium doesn't tell us which stage failed. The vertex shader is ours and
+            // rarely changes, so map lines through the fragment shader's source.
+            ProgramCreationError::CompilationError(log, ..) =>
+                ShaderError::Compile(source::parse_log(&log, fs_src)),
+            ProgramCreationError::LinkingError(log) => ShaderError::Link(log),
+            e => ShaderError::Compile(vec![Diagnostic {
+                file: None,
+                line: None,
+                message: e.to_string(),
+            }]),
+        })
+}
+
+fn read_shader<P>(path: &P) -> Result<String, ShaderError>
+    where P: AsRef<Path> {
+    fs::read_to_string(path)
+        .map_err(|e| ShaderError::Read(path.as_ref().to_path_buf(), e))
+}
+
+/// The fragment shader to create a program from.
+enum FragmentShader<'a> {
+    /// A shader file with a TOML block describing its uniforms.
+    Freeform(&'a Path),
+    /// The Image pass of a Shadertoy project.
+    Shadertoy(&'a Project),
+    /// The Sound pass of a Shadertoy project.
+    ShadertoySound(&'a Project),
+}
+
+fn create_program<F>(display: &F, vs_path: &Path, fragment_shader: FragmentShader) -> Result<(Program, Uniforms), ShaderError>
+    where F: Facade {
+    let vs_src = read_shader(&vs_path)?;
+    let vs_src = ShaderSource::from_file(vs_path, &vs_src);
+
+    match fragment_shader {
+        FragmentShader::Shadertoy(project) => {
+            let program = shadertoy::compile_pass(display, &vs_src, &project.image, project.common.as_ref())?;
+
+            let uniforms = ShadertoyUniforms::new();
+            Ok((program, Uniforms::Shadertoy(uniforms)))
+        }
+        FragmentShader::ShadertoySound(project) => {
+            // A lone shader file is loaded as the Image pass, but may well hold mainSound.
+            let pass = project.sound.as_ref().unwrap_or(&project.image);
+            let program = shadertoy::compile_sound_pass(display, &vs_src, pass, project.common.as_ref())?;
+
+            let uniforms = ShadertoyUniforms::new();
+            Ok((program, Uniforms::Shadertoy(uniforms)))
+        }
+        FragmentShader::Freeform(fs_path) => create_freeform_program(display, &vs_src, fs_path),
     }
-    //glium::Program::from_source(
-    //    midgar.graphics().display(),
-    //    &vertex_source,
-    //    &fragment_source,
-    //    None,
-    //).expect("Could not compile or link shader program")
 }
 
-fn create_program<F, P>(display: &F, vs_path: &P, fs_path: &P, shadertoy: bool) -> (Program, Uniforms)
-    where F: Facade, P: AsRef<Path> {
-    let vs_src = fs::read_to_string(&vs_path)
-        .expect("Could not open vertex shader file");
-    let fs_src = fs::read_to_string(&fs_path)
-        .expect("Could not open vertex shader file");
+/// A freeform shader split into its TOML block and the GLSL after it, which are missing if the
+/// file doesn't have them.
+struct FreeformSource<'a> {
+    toml: Option<&'a str>,
+    glsl: Option<&'a str>,
+    // Where the TOML and GLSL start in the file, so errors point at the right lines.
+    toml_first_line: u32,
+    glsl_first_line: u32,
+}
 
-    if shadertoy {
-        let fs_src = format!(
-            "#version 150 core
+fn split_freeform(src: &str) -> FreeformSource<'_> {
+    let mut parts = src.splitn(3, "+++\n");
+    // Value before the TOML block.
+    let preamble = parts.next()
+        .unwrap_or_default();
+    let toml = parts.next();
+    let glsl = parts.next();
+
+    let toml_first_line = preamble.matches('\n').count() as u32 + 2;
+    let glsl_first_line = toml_first_line + toml.map_or(0, |toml| toml.matches('\n').count() as u32) + 1;
+    FreeformSource {
+        toml,
+        glsl,
+        toml_first_line,
+        glsl_first_line,
+    }
+}
 
-            out vec4 color;
+fn create_freeform_program<F>(display: &F, vs_src: &ShaderSource, fs_path: &Path) -> Result<(Program, Uniforms), ShaderError>
+    where F: Facade {
+    let toml_error = |line, message: &str| ShaderError::Toml(fs_path.to_path_buf(), line, message.into());
+
+    let fs_src = read_shader(&fs_path)?;
+    let split_fs_src = split_freeform(&fs_src);
+    let toml_first_line = split_fs_src.toml_first_line;
+    let fs_first_line = split_fs_src.glsl_first_line;
+
+    let toml_src = split_fs_src.toml
+        .ok_or_else(|| toml_error(None, "Did not find TOML block"))?;
+
+    let parsed_toml: TomlValue = toml_src.parse()
+        .map_err(|e: toml::de::Error| {
+            let line = e.line_col().map(|(line, _)| toml_first_line + line as u32);
+            toml_error(line, &e.to_string())
+        })?;
+    eprintln!("Parsed TOML:\n{:#?}", parsed_toml);
+
+    let fs_src = split_fs_src.glsl
+        .ok_or_else(|| toml_error(Some(fs_first_line), "Did not find GLSL fragment shader source after TOML block"))?;
+    let mut source = ShaderSource::new();
+    source.push_file_with_includes(fs_path, fs_src, fs_first_line)?;
+    let program = compile_shader(display, vs_src, &source)?;
+    let mut uniforms = FreeformUniforms::new(&program);
+
+    if let TomlValue::Table(table) = parsed_toml {
+        for (key, value) in &table {
+            uniforms.apply_toml(key, value)
+                .map_err(|message| {
+                    let line = find_key_line(toml_src, key).map(|line| toml_first_line + line);
+                    toml_error(line, &message)
+                })?;
+        }
+    }
+    let base = fs_path.parent()
+        .unwrap_or_else(|| Path::new(""));
+    uniforms.load_textures(display, base)?;
 
-            uniform float iTime;
-            uniform float iGlobalTime;
-            uniform vec3 iResolution;
-            uniform vec4 iMouse;
-            uniform vec4 iDate;
+    eprintln!("Uniforms:\n{:?}", uniforms);
 
-            {}
+    Ok((program, Uniforms::Freeform(uniforms)))
+}
 
-            void main() {{
-                mainImage(color, gl_FragCoord.xy);
-            }}", fs_src);
+/// Find the line of a TOML block a top-level key is set on, counting from 0. Parsed TOML values
+/// don't remember where they came from, so this is just a search.
+fn find_key_line(toml_src: &str, key: &str) -> Option<u32> {
+    toml_src.lines()
+        .position(|line| {
+            let line = line.trim_start();
+            line.starts_with(key) && line[key.len()..].trim_start().starts_with('=')
+        })
+        .map(|line| line as u32)
+}
 
-        let program = compile_shader(display, &vs_src, &fs_src);
+/// Draw the shader's Image pass, or the freeform shader, over all of `target`. Shadertoy buffers
+/// have to be rendered first.
+fn draw_shader<S>(target: &mut S, vertex_buffer: &glium::VertexBuffer<Vertex>, index_buffer: &glium::IndexBuffer<u8>, program: &Program, uniforms: &mut Uniforms, multipass: &Multipass, keyboard: &Keyboard) -> Result<(), glium::DrawError>
+    where S: Surface {
+    match uniforms {
+        Uniforms::Shadertoy(uniforms) => target.draw(
+            vertex_buffer,
+            index_buffer,
+            program,
+            &multipass.image_uniforms(uniforms, keyboard),
+            &Default::default(),
+        ),
+        uniforms => target.draw(
+            vertex_buffer,
+            index_buffer,
+            program,
+            uniforms,
+            &Default::default(),
+        ),
+    }
+}
 
-        let uniforms = ShadertoyUniforms::new();
-        (program, Uniforms::Shadertoy(uniforms))
+/// A quad covering the whole target, for the fragment shader to run over.
+fn create_quad<F>(display: &F) -> (glium::VertexBuffer<Vertex>, glium::IndexBuffer<u8>)
+    where F: Facade {
+    let vertex_data = [
+        Vertex { vertex: [-1.0, -1.0] },
+        Vertex { vertex: [1.0, -1.0] },
+        Vertex { vertex: [1.0, 1.0] },
+        Vertex { vertex: [-1.0, 1.0] },
+    ];
+    let indices = [
+        0u8, 1, 3,
+        1, 2, 3,
+    ];
+    let vertex_buffer = glium::VertexBuffer::new(display, &vertex_data)
+        .expect("Could not create vertex buffer");
+    let index_buffer = glium::IndexBuffer::new(display, glium::index::PrimitiveType::TrianglesList, &indices)
+        .expect("Could not create index buffer");
+    (vertex_buffer, index_buffer)
+}
+
+/// Everything built from the shader files, loaded together so a failed reload can't leave it
+/// half updated.
+struct LoadedShader {
+    program: Program,
+    uniforms: Uniforms,
+    multipass: Multipass,
+    // Files the shader was built from.
+    paths: Vec<PathBuf>,
+}
+
+fn load_shader<F>(display: &F, vs_path: &Path, shader_path: &Path, shadertoy: bool, overrides: &Overrides, screen_size: (u32, u32)) -> Result<LoadedShader, ShaderError>
+    where F: Facade {
+    if shadertoy {
+        let project = Project::load(shader_path, overrides)?;
+        let (program, uniforms) = create_program(display, vs_path, FragmentShader::Shadertoy(&project))?;
+        let vs_src = ShaderSource::from_file(vs_path, &read_shader(&vs_path)?);
+        let multipass = Multipass::new(display, &vs_src, &project, screen_size)?;
+
+        let mut paths = project.paths();
+        paths.push(vs_path.to_path_buf());
+        Ok(LoadedShader {
+            program,
+            uniforms,
+            multipass,
+            paths,
+        })
     } else {
-        let mut split_fs_src = fs_src.split("+++\n");
-        // Value before the TOML block.
-        split_fs_src.next();
-
-        let toml_src = split_fs_src.next()
-            .expect("Did not find TOML block");
-        let parsed_toml: TomlValue = toml_src.parse()
-            .expect("Could not parse TOML block");
-        eprintln!("Parsed TOML:\n{:#?}", parsed_toml);
-
-        let fs_src = split_fs_src.next()
-            .expect("Did not find GLSL fragment shader source after TOML block");
-        let program = compile_shader(display, &vs_src, &fs_src);
-        let mut uniforms = FreeformUniforms::new(&program);
-
-        if let TomlValue::Table(table) = parsed_toml {
-            for (key, value) in &table {
-                if let Some(uniform) = uniforms.uniforms.iter_mut().find(|h| &h.name == key) {
-                    // TODO: Do stuff!
-                    match value {
-                        TomlValue::String(s) if s == "color" => {
-                            if let StormUniform::FloatVec3(_) = uniform.value {
-                                uniform.value = StormUniform::ColorRgb([1.0; 3]);
-                            } else if let StormUniform::FloatVec4(_) = uniform.value {
-                                uniform.value = StormUniform::ColorRgba([1.0; 4]);
-                            } else {
-                                // TODO: Print an error!
-                            }
-                        }
-                        TomlValue::String(s) if s == "resolution" => {
-                            if let StormUniform::FloatVec2(_) = uniform.value {
-                                uniform.value = StormUniform::Resolution([0.0; 2]);
-                            } else {
-                                // TODO: Print an error!
-                            }
-                        }
-                        TomlValue::Integer(toml_int) => {
-                            if let StormUniform::Int(uniform_int) = &mut uniform.value {
-                                *uniform_int = *toml_int as i32;
-                            }
-                        }
-                        //TomlValue::Float(f) => {}
-                        //TomlValue::Boolean(b) => {}
-                        //TomlValue::Array(arr) => {}
-                        _ => {}
-                    }
-                }
+        let (program, uniforms) = create_program(display, vs_path, FragmentShader::Freeform(shader_path))?;
+        let mut paths = vec![vs_path.to_path_buf(), shader_path.to_path_buf()];
+        // Only the GLSL, since TOML strings can look like #include lines too.
+        let fs_src = read_shader(&shader_path)?;
+        let split_fs_src = split_freeform(&fs_src);
+        if let Some(glsl) = split_fs_src.glsl {
+            paths.extend(source::dependencies(shader_path, glsl, split_fs_src.glsl_first_line));
+        }
+        // Reload when a texture changes too.
+        if let Uniforms::Freeform(freeform) = &uniforms {
+            paths.extend(freeform.texture_paths().cloned());
+        }
+        Ok(LoadedShader {
+            program,
+            uniforms,
+            multipass: Multipass::empty(),
+            paths,
+        })
+    }
+}
+
+/// Arguments for loading a shader, shared by the app and its subcommands.
+const SHADER_ARGS: &str =
+    "-s --shadertoy 'Treat provided shader as Shadertoy would.'
+    --channel0 [input] 'Image, audio file, buffer_a-d, cube_a or keyboard to bind to iChannel0 in Shadertoy mode.'
+    --channel1 [input] 'Image, audio file, buffer_a-d, cube_a or keyboard to bind to iChannel1 in Shadertoy mode.'
+    --channel2 [input] 'Image, audio file, buffer_a-d, cube_a or keyboard to bind to iChannel2 in Shadertoy mode.'
+    --channel3 [input] 'Image, audio file, buffer_a-d, cube_a or keyboard to bind to iChannel3 in Shadertoy mode.'
+    --common [file] 'Shadertoy Common code to prepend to every pass.'
+    --textures [dir] 'Directory with the textures and music used by a Shadertoy JSON export.'
+    <shader_file> 'The shader to run. Directories, .toml manifests and .json exports are run as Shadertoy shaders.'";
+
+fn cli() -> clap::App<'static, 'static> {
+    clap::App::new("Shade Storm")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
+        .args_from_usage(SHADER_ARGS)
+        .subcommand(clap::SubCommand::with_name("render")
+            .about("Render a frame to a PNG without a window, using OSMesa's software OpenGL.")
+            .args_from_usage(SHADER_ARGS)
+            .args_from_usage(
+                "-o --output <png> 'The PNG file to write.'
+                --width [pixels] 'Width of the image. Defaults to 1024.'
+                --height [pixels] 'Height of the image. Defaults to 768.'
+                --time [seconds] 'Time to render the frame at. Defaults to 0.'
+                --date [date] 'Date the shader sees at time 0, as YYYY-MM-DDTHH:MM:SS. Defaults to 2000-01-01T00:00:00.'"))
+        .subcommand(clap::SubCommand::with_name("export")
+            .about("Render an animation at a fixed frame rate without a window, using OSMesa's software OpenGL.")
+            .args_from_usage(SHADER_ARGS)
+            .args_from_usage(
+                "-o --output [pattern] 'Numbered PNG files to write, with {} replaced by the frame number, e.g. frames/{}.png.'
+                --pipe [command] 'Shell command to pipe frames to as raw RGBA, top row first, e.g. an ffmpeg command reading from stdin.'
+                --width [pixels] 'Width of the frames. Defaults to 1024.'
+                --height [pixels] 'Height of the frames. Defaults to 768.'
+                --start [seconds] 'Time of the first frame. Defaults to 0.'
+                --end [seconds] 'Time to stop at. Defaults to 10.'
+                --fps [rate] 'Frames per second. Defaults to 60.'
+                --date [date] 'Date the shader sees at time 0, as YYYY-MM-DDTHH:MM:SS. Defaults to 2000-01-01T00:00:00.'")
+            .group(clap::ArgGroup::with_name("destination")
+                .args(&["output", "pipe"])
+                .required(true)))
+        .subcommand(clap::SubCommand::with_name("sound")
+            .about("Render the Shadertoy Sound pass to a WAV file without a window, using OSMesa's software OpenGL.")
+            .args_from_usage(SHADER_ARGS)
+            .args_from_usage(
+                "-o --output <wav> 'The WAV file to write.'
+                --duration [seconds] 'Length of the audio. Defaults to 10.'
+                --sample-rate [rate] 'Sample rate of the audio. Defaults to 44100.'"))
+        .subcommand(clap::SubCommand::with_name("animate")
+            .about("Render a looping animated GIF or APNG without a window, using OSMesa's software OpenGL.")
+            .args_from_usage(SHADER_ARGS)
+            .args_from_usage(
+                "-o --output <file> 'The file to write, a GIF if it ends in .gif, otherwise an APNG.'
+                --width [pixels] 'Width of the animation. Defaults to 1024.'
+                --height [pixels] 'Height of the animation. Defaults to 768.'
+                --start [seconds] 'Time of the first frame. Defaults to 0.'
+                --end [seconds] 'Time to loop back at. Defaults to 10.'
+                --fps [rate] 'Frames per second. Defaults to 30.'
+                --date [date] 'Date the shader sees at time 0, as YYYY-MM-DDTHH:MM:SS. Defaults to 2000-01-01T00:00:00.'
+                --palette [palette] 'Colors to reduce frames to: global, frame (GIF only), web, gray or full (APNG only). Defaults to global.'
+                --dither [dither] 'How to dither frames to the palette: floyd-steinberg, ordered or none. Defaults to floyd-steinberg.'"))
+}
+
+/// Which shader to load and how, from the command line.
+struct ShaderOptions {
+    vs_path: PathBuf,
+    fs_path: PathBuf,
+    shadertoy: bool,
+    overrides: Overrides,
+}
+
+impl ShaderOptions {
+    fn from_args(args: &clap::ArgMatches) -> Self {
+        let vs_path = fs::canonicalize("src/shaders/simple.vert")
+            .expect("Could not canonicalize vertex shader path");
+        let fs_path = fs::canonicalize(args.value_of("shader_file")
+            .expect("Did not get a shader_file"))
+            .expect("Could not canonicalize fragment shader path");
+        let shadertoy = args.is_present("shadertoy")
+            || fs_path.is_dir()
+            || fs_path.extension().map_or(false, |ext| ext == "toml" || ext == "json");
+        let mut overrides = Overrides::default();
+        for (i, input) in overrides.channels.iter_mut().enumerate() {
+            if let Some(value) = args.value_of(format!("channel{}", i)) {
+                *input = Some(ChannelInput::parse(Path::new("."), value));
             }
         }
+        overrides.common = args.value_of("common").map(PathBuf::from);
+        overrides.textures = args.value_of("textures").map(PathBuf::from);
 
-        eprintln!("Uniforms:\n{:?}", uniforms);
+        Self {
+            vs_path,
+            fs_path,
+            shadertoy,
+            overrides,
+        }
+    }
+}
 
-        (program, Uniforms::Freeform(uniforms))
+/// Parse an optional command line value, exiting with an error if it's malformed.
+fn parse_arg<T>(args: &clap::ArgMatches, name: &str, default: T) -> T
+    where T: std::str::FromStr {
+    match args.value_of(name) {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Error: Invalid value \"{}\" for --{}", value, name);
+            process::exit(1);
+        }),
+        None => default,
     }
 }
 
 struct AppState {
     vs_path: PathBuf,
     fs_path: PathBuf,
-    watcher: RecommendedWatcher,
-    notify_rx: Receiver<DebouncedEvent>,
+    shadertoy: bool,
+    overrides: Overrides,
+    // The files the shader was built from, reloaded when one changes.
+    files: FileSet,
 
     program: glium::Program,
     uniforms: Uniforms,
+    multipass: Multipass,
+    // The error from the last failed recompile, if any.
+    shader_error: Option<ShaderError>,
     vertex_buffer: glium::VertexBuffer<Vertex>,
     index_buffer: glium::IndexBuffer<u8>,
 
     ui_data: UiData,
     fps_counter: FPSCounter,
+    keyboard: Keyboard,
 
     imgui: ImGui,
     ui_input_handler: ImguiSdl2,
@@ -400,47 +1315,32 @@ struct AppState {
 
 impl midgar::App for AppState {
     fn new(midgar: &Midgar) -> Self {
-        let args = clap::App::new("Shade Storm")
-            .args_from_usage(
-                "-s --shadertoy 'Treat provided shader as Shadertoy would.'
-                <shader_file> 'The shader to run.'")
-            .get_matches();
-
-        let vs_path = fs::canonicalize("src/shaders/simple.vert")
-            .expect("Could not canonicalize vertex shader path");
-        let fs_path = fs::canonicalize(args.value_of("shader_file")
-            .expect("Did not get a shader_file"))
-            .expect("Could not canonicalize fragment shader path");
-        let (program, uniforms) = create_program(midgar.graphics().display(), &vs_path, &fs_path, args.is_present("shadertoy"));
-
-        // Use notify to watch for changes in the shaders.
-        let (notify_tx, notify_rx) = mpsc::channel();
-        let mut watcher = notify::watcher(notify_tx, Duration::from_millis(500))
-            .expect("Could not create file watcher");
-        // NOTE: Watching the parent directory since watching a single file doesn't seem to work...
-        let vs_watch_path = vs_path.parent()
-            .expect("Could not watch vertex shader");
-        watcher.watch(vs_watch_path, RecursiveMode::Recursive)
-            .expect("Could not watch vertex shader");
-        let fs_watch_path = fs_path.parent()
-            .expect("Could not watch fragment shader");
-        watcher.watch(fs_watch_path, RecursiveMode::Recursive)
-            .expect("Could not watch fragment shader");
-
-        let vertex_data = [
-            Vertex { vertex: [-1.0, -1.0] },
-            Vertex { vertex: [1.0, -1.0] },
-            Vertex { vertex: [1.0, 1.0] },
-            Vertex { vertex: [-1.0, 1.0] },
-        ];
-        let indices = [
-            0u8, 1, 3,
-            1, 2, 3,
-        ];
-        let vertex_buffer = glium::VertexBuffer::new(midgar.graphics().display(), &vertex_data)
-            .expect("Could not create vertex buffer");
-        let index_buffer = glium::IndexBuffer::new(midgar.graphics().display(), glium::index::PrimitiveType::TrianglesList, &indices)
-            .expect("Could not create index buffer");
+        // midgar creates the app itself, so the command line is parsed again here. main has
+        // already handled the subcommands.
+        let args = cli().get_matches();
+        let ShaderOptions { vs_path, fs_path, shadertoy, overrides } = ShaderOptions::from_args(&args);
+
+        let LoadedShader { program, uniforms, multipass, paths } = load_shader(
+            midgar.graphics().display(),
+            &vs_path,
+            &fs_path,
+            shadertoy,
+            &overrides,
+            // Buffers are resized to the framebuffer every frame, so start them at its size.
+            midgar.graphics().display().get_context().get_framebuffer_dimensions(),
+        ).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        });
+
+        let mut files = FileSet::new();
+        files.set_paths(paths);
+
+        let (vertex_buffer, index_buffer) = create_quad(midgar.graphics().display());
+        let keyboard = Keyboard::new(midgar.graphics().display()).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        });
 
         let ui_data = UiData {
             global_time: 0.0,
@@ -448,6 +1348,10 @@ impl midgar::App for AppState {
             play: true,
             mouse_button_held: false,
             mouse_position: [0.0, 0.0],
+            shader_mouse_down: false,
+            shader_mouse: [0.0; 4],
+            ui_wants_mouse: false,
+            ui_wants_keyboard: false,
             date: Local::now(),
         };
 
@@ -465,14 +1369,18 @@ impl midgar::App for AppState {
         Self {
             vs_path: vs_path.into(),
             fs_path: fs_path.into(),
-            watcher,
-            notify_rx,
+            shadertoy,
+            overrides,
+            files,
             program,
             uniforms,
+            multipass,
+            shader_error: None,
             vertex_buffer,
             index_buffer,
             ui_data,
             fps_counter: FPSCounter::new(),
+            keyboard,
 
             imgui,
             ui_input_handler,
@@ -488,13 +1396,24 @@ impl midgar::App for AppState {
         }
 
         // imgui didn't handle the event, so we should!
-        if let Event::KeyDown { keycode: Some(KeyCode::Escape), ..} = event {
-            midgar.set_should_exit();
-            return;
+        match *event {
+            // Keys typed into a widget are meant for it, not for the shader or the shortcuts.
+            // Releases still go through so keys held when a widget took focus don't get stuck.
+            Event::KeyDown { .. } if self.ui_data.ui_wants_keyboard => return,
+            // Keep track of keys for Shadertoy keyboard inputs, shortcuts included, since games
+            // often use Space.
+            Event::KeyDown { keycode: Some(keycode), repeat, .. } => self.keyboard.key_down(keycode, repeat),
+            Event::KeyUp { keycode: Some(keycode), .. } => self.keyboard.key_up(keycode),
+            _ => {}
         }
 
-        if let Event::KeyDown { keycode: Some(KeyCode::Space), ..} = event {
-            self.ui_data.play = !self.ui_data.play;
+        match *event {
+            Event::KeyDown { keycode: Some(KeyCode::Escape), .. } => {
+                midgar.set_should_exit();
+                return;
+            }
+            Event::KeyDown { keycode: Some(KeyCode::Space), .. } => self.ui_data.play = !self.ui_data.play,
+            _ => {}
         }
 
         // Handle other global events.
@@ -515,55 +1434,71 @@ impl midgar::App for AppState {
     fn step(&mut self, midgar: &mut Midgar) {
         self.ui_data.mouse_button_held = midgar.input().is_button_held(MouseButton::Left);
 
+        // Convert the mouse position from window coordinates to framebuffer pixels with the
+        // origin at the bottom left, which differ on HiDPI displays.
         let (x, y) = midgar.input().mouse_pos();
-
-        if let Uniforms::Shadertoy(uniforms) = &mut self.uniforms {
-            if midgar.input().was_button_pressed(MouseButton::Left) {
-                uniforms.mouse[2] = x as f32;
-                uniforms.mouse[3] = y as f32;
-            }
-
+        let window_size = midgar.graphics().display().window().size();
+        let framebuffer_size = midgar.graphics().display().get_context().get_framebuffer_dimensions();
+        let scale = [
+            framebuffer_size.0 as f32 / window_size.0.max(1) as f32,
+            framebuffer_size.1 as f32 / window_size.1.max(1) as f32,
+        ];
+        let mouse_position = [x as f32 * scale[0], framebuffer_size.1 as f32 - y as f32 * scale[1]];
+        self.ui_data.mouse_position = [mouse_position[0] as f64, mouse_position[1] as f64];
+
+        // Follow Shadertoy's conventions: xy is the position while the button is held, zw is
+        // where it was clicked. z is negative once the button is released and w is only positive
+        // on the frame of the click.
+        let shader_mouse = &mut self.ui_data.shader_mouse;
+        if midgar.input().was_button_pressed(MouseButton::Left) && !self.ui_data.ui_wants_mouse {
+            self.ui_data.shader_mouse_down = true;
+            *shader_mouse = [mouse_position[0], mouse_position[1], mouse_position[0], mouse_position[1]];
+        } else if self.ui_data.shader_mouse_down {
             if midgar.input().is_button_held(MouseButton::Left) {
-                uniforms.mouse[0] = x as f32;
-                uniforms.mouse[1] = y as f32;
+                shader_mouse[0] = mouse_position[0];
+                shader_mouse[1] = mouse_position[1];
+                shader_mouse[3] = -shader_mouse[3].abs();
+            } else {
+                self.ui_data.shader_mouse_down = false;
+                shader_mouse[2] = -shader_mouse[2].abs();
+                shader_mouse[3] = -shader_mouse[3].abs();
             }
         }
 
         // Check if shaders changed, if so, recompile them.
-        let recompile_shaders = {
-            let mut ret = false;
-            while let Ok(event) = self.notify_rx.try_recv() {
-                eprintln!("Got file event: {:?}", &event);
-                match event {
-                    DebouncedEvent::Write(path) | DebouncedEvent::Create(path) => {
-                        if path == self.vs_path || path == self.fs_path {
-                            ret = true;
-                        }
-                    },
-                    DebouncedEvent::Remove(path) => {
-                        if path == self.vs_path || path == self.fs_path {
-                            eprintln!("In-use shader \"{}\" removed! Exiting...", path.display());
-                            midgar.set_should_exit();
-                            return;
-                        }
-                    },
-                    _ => {},
-                }
-            }
-            ret
-        };
+        let recompile_shaders = self.files.changed();
 
         if recompile_shaders {
             eprint!("Recompiling shaders... ");
-            let shadertoy = if let Uniforms::Shadertoy(_) = self.uniforms {
-                true
-            } else {
-                false
-            };
-            let (program, uniforms) = create_program(midgar.graphics().display(), &self.vs_path, &self.fs_path, shadertoy);
-            self.program = program;
-            self.uniforms = uniforms;
-            eprintln!("Done!");
+            // Keep drawing the last good program if the new one fails to build.
+            let loaded = load_shader(
+                midgar.graphics().display(),
+                &self.vs_path,
+                &self.fs_path,
+                self.shadertoy,
+                &self.overrides,
+                framebuffer_size,
+            );
+            match loaded {
+                Ok(mut loaded) => {
+                    // Don't throw away what was dialed in with the widgets.
+                    if let (Uniforms::Freeform(new), Uniforms::Freeform(old)) = (&mut loaded.uniforms, &self.uniforms) {
+                        new.keep_tweaks(old);
+                    }
+                    self.program = loaded.program;
+                    self.uniforms = loaded.uniforms;
+                    self.multipass = loaded.multipass;
+                    self.files.set_paths(loaded.paths);
+                    self.shader_error = None;
+                    eprintln!("Done!");
+                }
+                Err(e) => {
+                    eprintln!("Failed!\nError: {}", e);
+                    // Files with problems might not be in the set yet, like a newly included one.
+                    self.files.add_paths(e.diagnostics().into_iter().filter_map(|d| d.file));
+                    self.shader_error = Some(e);
+                }
+            }
         }
 
         if !self.ui_data.play && !recompile_shaders {
@@ -572,31 +1507,17 @@ impl midgar::App for AppState {
 
         // Update uniform values.
         self.ui_data.date = Local::now();
-        let screen_size = midgar.graphics().screen_size();
-        match &mut self.uniforms {
-            Uniforms::Freeform(uniforms) => {
-                for holder in &mut uniforms.uniforms {
-                    match &mut holder.value {
-                        StormUniform::Resolution(v) =>
-                            *v = [screen_size.0 as f32, screen_size.1 as f32],
-                        _ => {}
-                    }
-                }
-            }
-            Uniforms::Shadertoy(uniforms) => {
-                uniforms.resolution = [screen_size.0 as f32, screen_size.1 as f32, 1.0];
-                uniforms.time_delta = midgar.time().delta_time() as f32;
-                //self.ui_data.global_time += self.uniforms.time_delta;
-                uniforms.time += uniforms.time_delta;
-                uniforms.frame += 1;
-                uniforms.frame_rate = self.fps_counter.tick() as f32;
-                uniforms.date = [
-                    self.ui_data.date.year() as f32,
-                    self.ui_data.date.month0() as f32,
-                    self.ui_data.date.day0() as f32,
-                    self.ui_data.date.num_seconds_from_midnight() as f32,
-                ];
-            }
+        let screen_size = framebuffer_size;
+        let time_delta = midgar.time().delta_time() as f32;
+        self.uniforms.update(&FrameInputs {
+            resolution: [screen_size.0 as f32, screen_size.1 as f32],
+            time: self.uniforms.time() + time_delta,
+            time_delta,
+            mouse: self.ui_data.shader_mouse,
+            date: date_uniform(&self.ui_data.date),
+        });
+        if let Uniforms::Shadertoy(uniforms) = &mut self.uniforms {
+            uniforms.frame_rate = self.fps_counter.tick() as f32;
         }
 
         // Update UI.
@@ -605,59 +1526,93 @@ impl midgar::App for AppState {
             midgar.graphics().display().window(),
             imgui,
             &midgar.input().mouse_state());
+        self.ui_data.ui_wants_mouse = ui.want_capture_mouse();
+        self.ui_data.ui_wants_keyboard = ui.want_capture_keyboard();
 
         // Show a window with options for the shader.
         // TODO: Can we dock the window to a side?
         let uniforms = &mut self.uniforms;
         ui.window(im_str!("Shader Options"))
+            .position((10.0, 10.0), ImGuiCond::FirstUseEver)
             .size((300.0, 100.0), ImGuiCond::FirstUseEver)
             .build(|| {
                 if let Uniforms::Freeform(uniforms) = uniforms {
-                    for holder in &mut uniforms.uniforms {
-                        // Create a widget to modify the uniform.
-                        match &mut holder.value {
-                            StormUniform::Int(i) => {
-                                ui.slider_int(im_str!("{}", &holder.name), i, 0, 500)
-                                    .build();
-                            }
-                            StormUniform::ColorRgb(c) => {
-                                ui.color_edit(im_str!("{}", &holder.name), c)
-                                    .build();
-                            }
-                            StormUniform::ColorRgba(c) => {
-                                ui.color_edit(im_str!("{}", &holder.name), c)
-                                    .build();
-                            }
-                            _ => {}
-                        }
+                    if ui.button(im_str!("Reset to defaults"), (0.0, 0.0)) {
+                        uniforms.reset_to_defaults();
                     }
+                    uniforms.draw_widgets(&ui);
                 }
             });
 
+        // Show what went wrong with the last recompile or draw next to the options.
+        if let Some(shader_error) = &self.shader_error {
+            ui.window(im_str!("Shader Errors"))
+                .position((320.0, 10.0), ImGuiCond::FirstUseEver)
+                .size((500.0, 200.0), ImGuiCond::FirstUseEver)
+                .build(|| {
+                    for diagnostic in shader_error.diagnostics() {
+                        let location = match (&diagnostic.file, diagnostic.line) {
+                            (Some(file), Some(line)) => format!("{}:{}", file.display(), line),
+                            (Some(file), None) => format!("{}", file.display()),
+                            (None, Some(line)) => format!("<generated>:{}", line),
+                            (None, None) => String::new(),
+                        };
+                        if !location.is_empty() {
+                            ui.text_colored([1.0, 0.4, 0.4, 1.0], im_str!("{}", location));
+                        }
+                        ui.text_wrapped(im_str!("{}", &diagnostic.message));
+                        ui.separator();
+                    }
+                });
+        }
+
         // Render everything!
         {
+            // Shadertoy buffers have to be rendered before the Image pass can read them.
+            if let Uniforms::Shadertoy(uniforms) = &mut self.uniforms {
+                self.keyboard.update();
+                // Time stands still while paused, so audio inputs pause along with it.
+                self.multipass.update_audio(uniforms.time);
+                // The Image pass still draws if the buffers can't, so the session keeps going
+                // and the error shows up next frame.
+                let result = match self.multipass.resize(midgar.graphics().display(), screen_size) {
+                    Ok(()) => self.multipass.render_buffers(midgar.graphics().display(), &self.vertex_buffer, &self.index_buffer, uniforms, &self.keyboard),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    // Only report it once rather than every frame.
+                    if self.shader_error.is_none() {
+                        eprintln!("Error: {}", e);
+                    }
+                    self.shader_error = Some(e);
+                }
+            }
+
             let mut target = midgar.graphics().display().draw();
 
             // TODO: Allow the shader to set what to clear the screen to.
             target.clear_color(0.0, 0.0, 0.0, 1.0);
 
-            // Run the shader.
-            target.draw(
-                &self.vertex_buffer,
-                &self.index_buffer,
-                &self.program,
-                &self.uniforms,
-                &Default::default(),
-            ).expect("Could not draw to screen");
+            // Run the shader. Like the buffers, a failed draw shows up next frame rather than
+            // ending the session.
+            let result = draw_shader(&mut target, &self.vertex_buffer, &self.index_buffer, &self.program, &mut self.uniforms, &self.multipass, &self.keyboard);
+            if let Err(e) = result {
+                let e = ShaderError::Draw(e.to_string());
+                if self.shader_error.is_none() {
+                    eprintln!("Error: {}", e);
+                }
+                self.shader_error = Some(e);
+            }
 
             // Draw the UI.
             self.ui_renderer.render(&mut target, ui)
                 .expect("Could not render UI");
 
             // TODO: Move this somewhere earlier?
-            if let Uniforms::Shadertoy(uniforms) = &mut self.uniforms {
+            if let Uniforms::Shadertoy(uniforms) = &self.uniforms {
                 self.ui_data.fps = uniforms.frame_rate;
             }
+            self.uniforms.finish_frame();
 
             target.finish()
                 .expect("target.finish() failed");
@@ -665,7 +1620,28 @@ impl midgar::App for AppState {
     }
 }
 
+/// The date laid out like Shadertoy's iDate: year, month, day and seconds since midnight.
+fn date_uniform<D>(date: &D) -> [f32; 4]
+    where D: Datelike + Timelike {
+    [
+        date.year() as f32,
+        // Shadertoy's month is 0-based but its day is 1-based.
+        date.month0() as f32,
+        date.day() as f32,
+        date.num_seconds_from_midnight() as f32 + date.nanosecond() as f32 / 1_000_000_000.0,
+    ]
+}
+
 fn main() {
+    let args = cli().get_matches();
+    match args.subcommand() {
+        ("render", Some(render_args)) => return headless::render_command(render_args),
+        ("export", Some(export_args)) => return headless::export_command(export_args),
+        ("sound", Some(sound_args)) => return headless::sound_command(sound_args),
+        ("animate", Some(animate_args)) => return headless::animate_command(animate_args),
+        _ => {}
+    }
+
     let config = midgar::MidgarAppConfig::new()
         .with_title("Shade Storm")
         .with_screen_size(SCREEN_SIZE)
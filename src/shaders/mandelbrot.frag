@@ -1,8 +1,7 @@
 +++
 resolution = "resolution"
 color = "color"
-iterations = 50
-#iterations = { widget = "slider", min = 1 }
+iterations = { widget = "slider", min = 1, max = 500, default = 50 }
 +++
 
 uniform vec2 resolution;
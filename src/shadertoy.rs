@@ -0,0 +1,822 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glium::{IndexBuffer, Program, Surface, Texture2d, VertexBuffer};
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{CubeLayer, Cubemap, MipmapsOption, UncompressedFloatFormat};
+use glium::uniforms::{
+    AsUniformValue, MagnifySamplerFilter, MinifySamplerFilter, SamplerBehavior, SamplerWrapFunction,
+    Uniforms as GliumUniforms, UniformValue,
+};
+use toml::Value as TomlValue;
+
+use crate::{ShaderError, ShadertoyUniforms, Vertex, compile_shader, read_shader};
+use crate::audio::{self, Audio};
+use crate::keyboard::Keyboard;
+use crate::source::{self, ShaderSource};
+use crate::texture::{self, CUBE_LAYERS, CubemapImages, Filter, ImageTexture, TextureOptions, Wrap};
+
+mod json;
+
+pub const CHANNEL_COUNT: usize = 4;
+pub const BUFFER_COUNT: usize = 4;
+
+const CHANNEL_NAMES: [&str; CHANNEL_COUNT] = ["iChannel0", "iChannel1", "iChannel2", "iChannel3"];
+
+/// Names of the buffer passes, as used for manifest tables, channel inputs and file names.
+const BUFFER_NAMES: [&str; BUFFER_COUNT] = ["buffer_a", "buffer_b", "buffer_c", "buffer_d"];
+
+/// Name of the Cube A pass, as used for its manifest table, channel inputs and file name.
+const CUBE_NAME: &str = "cube_a";
+
+/// Name of the manifest looked up when the shader is a directory.
+const MANIFEST_NAME: &str = "shadertoy.toml";
+
+// Wraps a Shadertoy shader's mainImage in a regular fragment shader.
+const SHADERTOY_HEADER: &str = "#version 150 core
+
+out vec4 color;
+
+uniform vec3 iResolution;
+uniform float iTime;
+uniform float iGlobalTime;
+uniform float iTimeDelta;
+uniform float iFrameRate;
+uniform int iFrame;
+uniform float iChannelTime[4];
+uniform vec3 iChannelResolution[4];
+uniform vec4 iMouse;
+uniform vec4 iDate;
+uniform float iSampleRate;
+";
+
+const SHADERTOY_FOOTER: &str = "
+void main() {
+    mainImage(color, gl_FragCoord.xy);
+}
+";
+
+// Wraps a Shadertoy Sound pass's mainSound. Each fragment of the target computes one stereo
+// sample, counting from iSoundBlockOffset along rows of SOUND_BLOCK_WIDTH fragments.
+const SOUND_FOOTER: &str = "
+uniform int iSoundBlockOffset;
+
+void main() {
+    int samp = iSoundBlockOffset + int(gl_FragCoord.y) * 512 + int(gl_FragCoord.x);
+    color = vec4(mainSound(samp, float(samp) / iSampleRate), 0.0, 1.0);
+}
+";
+
+/// Width of the target a Sound pass is rendered into, must match SOUND_FOOTER.
+pub const SOUND_BLOCK_WIDTH: u32 = 512;
+
+// Wraps a Shadertoy Cube A pass's mainCubemap. Each face is drawn separately, with rays going
+// through its texels the way GL maps directions to cubemap faces.
+const CUBE_FOOTER: &str = "
+uniform int iCubeFace;
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / iResolution.xy * 2.0 - 1.0;
+    vec3 dir;
+    if (iCubeFace == 0) dir = vec3(1.0, -uv.y, -uv.x);
+    else if (iCubeFace == 1) dir = vec3(-1.0, -uv.y, uv.x);
+    else if (iCubeFace == 2) dir = vec3(uv.x, 1.0, uv.y);
+    else if (iCubeFace == 3) dir = vec3(uv.x, -1.0, -uv.y);
+    else if (iCubeFace == 4) dir = vec3(uv.x, -uv.y, 1.0);
+    else dir = vec3(-uv.x, -uv.y, -1.0);
+    mainCubemap(color, gl_FragCoord.xy, vec3(0.0), normalize(dir));
+}
+";
+
+/// Size of each face of the Cube A pass's target, like on Shadertoy.
+const CUBE_SIZE: u32 = 1024;
+
+/// Compile a Shadertoy pass, wrapping its source so it can be run as a regular fragment shader.
+/// The Common code, if any, goes between the wrapper's declarations and the pass's source.
+pub fn compile_pass<F>(display: &F, vs_src: &ShaderSource, pass: &PassConfig, common: Option<&PassSource>) -> Result<Program, ShaderError>
+    where F: Facade {
+    compile_wrapped(display, vs_src, pass, common, SHADERTOY_FOOTER)
+}
+
+/// Compile a Shadertoy Sound pass, see `sound::render_wav` for how it's drawn.
+pub fn compile_sound_pass<F>(display: &F, vs_src: &ShaderSource, pass: &PassConfig, common: Option<&PassSource>) -> Result<Program, ShaderError>
+    where F: Facade {
+    compile_wrapped(display, vs_src, pass, common, SOUND_FOOTER)
+}
+
+fn compile_cube_pass<F>(display: &F, vs_src: &ShaderSource, pass: &PassConfig, common: Option<&PassSource>) -> Result<Program, ShaderError>
+    where F: Facade {
+    compile_wrapped(display, vs_src, pass, common, CUBE_FOOTER)
+}
+
+fn compile_wrapped<F>(display: &F, vs_src: &ShaderSource, pass: &PassConfig, common: Option<&PassSource>, footer: &str) -> Result<Program, ShaderError>
+    where F: Facade {
+    let fs_src = pass.source.read()?;
+
+    let mut source = ShaderSource::new();
+    source.push_generated(SHADERTOY_HEADER);
+    // Channels are declared with the sampler type of whatever is bound to them.
+    let mut samplers = String::new();
+    for (name, input) in CHANNEL_NAMES.iter().zip(&pass.inputs) {
+        let sampler = match input {
+            Some(input) if input.is_cube() => "samplerCube",
+            _ => "sampler2D",
+        };
+        samplers.push_str(&format!("uniform {} {};\n", sampler, name));
+    }
+    source.push_generated(&samplers);
+    if let Some(common) = common {
+        let common_src = common.read()?;
+        source.push_file_with_includes(common.path(), &common_src, 1)?;
+    }
+    source.push_file_with_includes(pass.source.path(), &fs_src, 1)?;
+    source.push_generated(footer);
+
+    compile_shader(display, vs_src, &source)
+}
+
+/// Where a pass's code comes from.
+#[derive(Clone, Debug)]
+pub enum PassSource {
+    File(PathBuf),
+    /// Code embedded in another file, like a Shadertoy JSON export. The path labels it in errors.
+    Embedded(PathBuf, String),
+}
+
+impl PassSource {
+    pub fn path(&self) -> &Path {
+        match self {
+            PassSource::File(path) => path,
+            PassSource::Embedded(path, _) => path,
+        }
+    }
+
+    fn read(&self) -> Result<String, ShaderError> {
+        match self {
+            PassSource::File(path) => read_shader(path),
+            PassSource::Embedded(_, code) => Ok(code.clone()),
+        }
+    }
+
+    /// The file to watch for changes, if the code lives in its own file.
+    fn file(&self) -> Option<&Path> {
+        match self {
+            PassSource::File(path) => Some(path),
+            PassSource::Embedded(..) => None,
+        }
+    }
+}
+
+/// What a Shadertoy iChannel is bound to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelInput {
+    /// An image file loaded as a 2D texture.
+    Texture(PathBuf, TextureOptions),
+    /// A WAV or Ogg Vorbis file, analyzed into a spectrum and waveform texture, see `Audio`.
+    Audio(PathBuf),
+    /// Images loaded as a cubemap.
+    Cubemap(CubemapImages, TextureOptions),
+    /// The output of one of the Buffer A-D passes.
+    Buffer(usize),
+    /// The output of the Cube A pass.
+    CubeBuffer,
+    /// Keyboard state, see `Keyboard`.
+    Keyboard,
+}
+
+impl ChannelInput {
+    /// Parse a channel input as written in a manifest or on the command line. Buffers are named
+    /// "buffer_a" to "buffer_d", the Cube A pass is "cube_a", "keyboard" is the keyboard and
+    /// anything else is a path to an audio file or image relative to `base`.
+    pub fn parse(base: &Path, input: &str) -> Self {
+        match input {
+            "keyboard" => return ChannelInput::Keyboard,
+            CUBE_NAME => return ChannelInput::CubeBuffer,
+            _ => {}
+        }
+        match BUFFER_NAMES.iter().position(|&name| name == input) {
+            Some(i) => ChannelInput::Buffer(i),
+            None if audio::is_audio_path(Path::new(input)) => ChannelInput::Audio(base.join(input)),
+            None => ChannelInput::Texture(base.join(input), TextureOptions::default()),
+        }
+    }
+
+    /// Parse a cubemap channel input from a manifest, a table like
+    /// `{ cubemap = "sky.png", filter = "linear", wrap = "clamp" }`. The cubemap is either a
+    /// cross layout image or an array of six face images.
+    fn parse_table(base: &Path, table: &TomlValue) -> Result<Self, String> {
+        let images = match table.get("cubemap") {
+            Some(TomlValue::String(s)) => CubemapImages::Cross(base.join(s)),
+            Some(TomlValue::Array(faces)) if faces.len() == 6 => {
+                let mut paths: [PathBuf; 6] = Default::default();
+                for (path, face) in paths.iter_mut().zip(faces) {
+                    match face {
+                        TomlValue::String(s) => *path = base.join(s),
+                        _ => return Err("cubemap faces must be paths".into()),
+                    }
+                }
+                CubemapImages::Faces(paths)
+            }
+            _ => return Err("channel tables need a \"cubemap\" path or array of six paths".into()),
+        };
+
+        let mut options = TextureOptions {
+            vflip: false,
+            ..Default::default()
+        };
+        if let Some(filter) = table.get("filter") {
+            options.filter = filter.as_str()
+                .and_then(Filter::parse)
+                .ok_or("\"filter\" must be nearest, linear or mipmap")?;
+        }
+        if let Some(wrap) = table.get("wrap") {
+            options.wrap = wrap.as_str()
+                .and_then(Wrap::parse)
+                .ok_or("\"wrap\" must be repeat, clamp or mirror")?;
+        }
+        Ok(ChannelInput::Cubemap(images, options))
+    }
+
+    /// Whether the input is sampled as a samplerCube rather than a sampler2D.
+    pub fn is_cube(&self) -> bool {
+        match self {
+            ChannelInput::Cubemap(..) | ChannelInput::CubeBuffer => true,
+            _ => false,
+        }
+    }
+}
+
+pub type ChannelInputs = [Option<ChannelInput>; CHANNEL_COUNT];
+
+/// Settings from the command line that take precedence over a project's config.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Inputs for the Image pass.
+    pub channels: ChannelInputs,
+    pub common: Option<PathBuf>,
+    /// Where to look for the textures and music of a Shadertoy JSON export.
+    pub textures: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct PassConfig {
+    pub source: PassSource,
+    pub inputs: ChannelInputs,
+}
+
+/// The passes that make up a Shadertoy shader.
+///
+/// A project is loaded from one of:
+///
+/// * A single shader file, with an optional sidecar config next to it (`foo.toml` for
+///   `foo.frag`).
+/// * A manifest `.toml` file.
+/// * A directory containing `image.glsl` and optionally `common.glsl`, `buffer_a.glsl` to
+///   `buffer_d.glsl`, `cube_a.glsl` and `sound.glsl` (`.frag` also works), plus an optional
+///   `shadertoy.toml` manifest.
+/// * A `.json` export from Shadertoy's API or a browser extension.
+///
+/// Configs have an optional `common` source that is prepended to every pass, and one table per
+/// pass, each with an optional `source` and `channel0`..`channel3`. Channels are strings, see
+/// `ChannelInput::parse`, or tables for cubemaps:
+///
+/// ```toml
+/// common = "common.glsl"
+///
+/// [image]
+/// source = "image.glsl"
+/// channel0 = "buffer_a"
+///
+/// [buffer_a]
+/// source = "buffer_a.glsl"
+/// channel0 = "buffer_a"
+/// channel1 = "textures/noise.png"
+///
+/// [cube_a]
+/// source = "cube_a.glsl"
+/// channel0 = { cubemap = "textures/sky_cross.png", filter = "linear" }
+///
+/// [sound]
+/// source = "sound.glsl"
+/// ```
+#[derive(Debug)]
+pub struct Project {
+    pub image: PassConfig,
+    pub buffers: [Option<PassConfig>; BUFFER_COUNT],
+    /// The Cube A pass, rendered into a cubemap.
+    pub cube: Option<PassConfig>,
+    /// The Sound pass, only used when rendering audio.
+    pub sound: Option<PassConfig>,
+    pub common: Option<PassSource>,
+    config_path: Option<PathBuf>,
+}
+
+impl Project {
+    pub fn load(shader_path: &Path, overrides: &Overrides) -> Result<Self, ShaderError> {
+        let mut project = if shader_path.extension().map_or(false, |ext| ext == "json") {
+            let textures = overrides.textures.as_ref()
+                .map(|p| p.as_path())
+                .or_else(|| shader_path.parent())
+                .unwrap_or_else(|| Path::new("."));
+            json::load(shader_path, textures)?
+        } else {
+            Self::load_config(shader_path)?
+        };
+
+        if let Some(common) = &overrides.common {
+            let common = fs::canonicalize(common)
+                .map_err(|e| ShaderError::Read(common.clone(), e))?;
+            project.common = Some(PassSource::File(common));
+        }
+        for (input, channel_override) in project.image.inputs.iter_mut().zip(&overrides.channels) {
+            if channel_override.is_some() {
+                *input = channel_override.clone();
+            }
+        }
+
+        Ok(project)
+    }
+
+    fn load_config(shader_path: &Path) -> Result<Self, ShaderError> {
+        let base = if shader_path.is_dir() {
+            shader_path
+        } else {
+            shader_path.parent().unwrap_or_else(|| Path::new("."))
+        };
+        let (config_path, default_image) = if shader_path.is_dir() {
+            (shader_path.join(MANIFEST_NAME), find_pass_source(base, "image"))
+        } else if shader_path.extension().map_or(false, |ext| ext == "toml") {
+            (shader_path.to_path_buf(), None)
+        } else {
+            (sidecar_path(shader_path), Some(shader_path.to_path_buf()))
+        };
+
+        let config = if config_path.is_file() {
+            Some(read_config(&config_path)?)
+        } else {
+            None
+        };
+        let pass_table = |name: &str| config.as_ref().and_then(|c| c.get(name));
+
+        let common = match config.as_ref().and_then(|c| c.get("common")) {
+            Some(TomlValue::String(s)) => Some(base.join(s)),
+            Some(_) => {
+                let message = "\"common\" must be a path to a shader".into();
+                return Err(ShaderError::Toml(config_path, None, message));
+            }
+            None if shader_path.is_dir() => find_pass_source(base, "common"),
+            None => None,
+        };
+        let common = match common {
+            Some(common) => Some(PassSource::File(fs::canonicalize(&common)
+                .map_err(|e| ShaderError::Read(common, e))?)),
+            None => None,
+        };
+
+        let image = parse_pass(&config_path, base, pass_table("image"), default_image)?
+            .ok_or_else(|| {
+                let missing = io::Error::new(io::ErrorKind::NotFound, "No source for the image pass");
+                ShaderError::Read(config_path.clone(), missing)
+            })?;
+
+        let mut buffers: [Option<PassConfig>; BUFFER_COUNT] = Default::default();
+        // Only directories pick up passes by file name, otherwise they must be configured.
+        let default_source = |name: &str| if shader_path.is_dir() {
+            find_pass_source(base, name)
+        } else {
+            None
+        };
+        for (buffer, &name) in buffers.iter_mut().zip(BUFFER_NAMES.iter()) {
+            *buffer = parse_pass(&config_path, base, pass_table(name), default_source(name))?;
+        }
+        let cube = parse_pass(&config_path, base, pass_table(CUBE_NAME), default_source(CUBE_NAME))?;
+        let sound = parse_pass(&config_path, base, pass_table("sound"), default_source("sound"))?;
+
+        Ok(Self {
+            image,
+            buffers,
+            cube,
+            sound,
+            common,
+            config_path: if config.is_some() { Some(config_path) } else { None },
+        })
+    }
+
+    /// Every file the project was built from, to know when to reload it.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.config_path.iter().cloned().collect();
+        let sources = self.common.iter()
+            .chain(Some(&self.image.source))
+            .chain(self.buffers.iter().flatten().map(|buffer| &buffer.source))
+            .chain(self.cube.iter().map(|cube| &cube.source))
+            .chain(self.sound.iter().map(|sound| &sound.source));
+        for pass_source in sources {
+            if let Some(path) = pass_source.file() {
+                paths.push(path.to_path_buf());
+            }
+            // Included files are watched too. Problems with them are reported when compiling.
+            if let Ok(text) = pass_source.read() {
+                paths.extend(source::dependencies(pass_source.path(), &text, 1));
+            }
+        }
+        paths
+    }
+}
+
+/// The optional config that sits next to a single Shadertoy shader file.
+fn sidecar_path(fs_path: &Path) -> PathBuf {
+    fs_path.with_extension("toml")
+}
+
+fn find_pass_source(dir: &Path, name: &str) -> Option<PathBuf> {
+    ["glsl", "frag"].iter()
+        .map(|ext| dir.join(name).with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+fn read_config(path: &Path) -> Result<TomlValue, ShaderError> {
+    let src = read_shader(&path)?;
+    src.parse()
+        .map_err(|e: toml::de::Error| {
+            let line = e.line_col().map(|(line, _)| line as u32 + 1);
+            ShaderError::Toml(path.to_path_buf(), line, e.to_string())
+        })
+}
+
+/// Read a pass table, falling back to `default_source` when it doesn't name a source. Returns
+/// None if the pass has no source at all.
+fn parse_pass(config_path: &Path, base: &Path, table: Option<&TomlValue>, default_source: Option<PathBuf>) -> Result<Option<PassConfig>, ShaderError> {
+    let toml_error = |message: String| ShaderError::Toml(config_path.to_path_buf(), None, message);
+
+    let source = match table.and_then(|t| t.get("source")) {
+        Some(TomlValue::String(s)) => Some(base.join(s)),
+        Some(_) => return Err(toml_error("\"source\" must be a path to a shader".into())),
+        None => default_source,
+    };
+    let source = match source {
+        Some(source) => PassSource::File(fs::canonicalize(&source)
+            .map_err(|e| ShaderError::Read(source, e))?),
+        None => return Ok(None),
+    };
+
+    let mut inputs = ChannelInputs::default();
+    if let Some(table) = table {
+        for (i, input) in inputs.iter_mut().enumerate() {
+            let key = format!("channel{}", i);
+            match table.get(&key) {
+                Some(TomlValue::String(s)) => *input = Some(ChannelInput::parse(base, s)),
+                Some(t @ TomlValue::Table(_)) => *input = Some(ChannelInput::parse_table(base, t)
+                    .map_err(|e| toml_error(format!("\"{}\": {}", key, e)))?),
+                Some(_) => return Err(toml_error(format!("\"{}\" must be a string or table", key))),
+                None => {}
+            }
+        }
+    }
+
+    Ok(Some(PassConfig {
+        source,
+        inputs,
+    }))
+}
+
+/// A channel input, loaded and ready to be bound.
+enum Binding {
+    Texture(ImageTexture, SamplerBehavior),
+    Cubemap(Cubemap, SamplerBehavior),
+    Audio(Audio),
+    Buffer(usize),
+    CubeBuffer,
+    Keyboard,
+}
+
+impl Binding {
+    fn load<F>(display: &F, input: &ChannelInput) -> Result<Self, ShaderError>
+        where F: Facade {
+        match input {
+            ChannelInput::Texture(path, options) => {
+                let texture = texture::load_texture(display, path, options)?;
+                Ok(Binding::Texture(texture, options.sampler()))
+            }
+            ChannelInput::Cubemap(images, options) => {
+                let cubemap = texture::load_cubemap(display, images)?;
+                Ok(Binding::Cubemap(cubemap, options.cubemap_sampler()))
+            }
+            ChannelInput::Audio(path) => Ok(Binding::Audio(Audio::load(display, path)?)),
+            ChannelInput::Buffer(i) => Ok(Binding::Buffer(*i)),
+            ChannelInput::CubeBuffer => Ok(Binding::CubeBuffer),
+            ChannelInput::Keyboard => Ok(Binding::Keyboard),
+        }
+    }
+}
+
+type Bindings = [Option<Binding>; CHANNEL_COUNT];
+
+fn load_bindings<F>(display: &F, inputs: &ChannelInputs) -> Result<Bindings, ShaderError>
+    where F: Facade {
+    let mut bindings = Bindings::default();
+    for (binding, input) in bindings.iter_mut().zip(inputs) {
+        if let Some(input) = input {
+            *binding = Some(Binding::load(display, input)?);
+        }
+    }
+    Ok(bindings)
+}
+
+/// One of the Buffer A-D passes, rendering into a pair of float textures so it can read its
+/// own previous frame.
+struct BufferPass {
+    program: Program,
+    bindings: Bindings,
+    targets: [Texture2d; 2],
+    // Index of the target holding the most recently rendered frame.
+    current: usize,
+}
+
+impl BufferPass {
+    fn output(&self) -> &Texture2d {
+        &self.targets[self.current]
+    }
+}
+
+fn create_target<F>(display: &F, size: (u32, u32)) -> Result<Texture2d, ShaderError>
+    where F: Facade {
+    let texture = Texture2d::empty_with_format(display, UncompressedFloatFormat::F32F32F32F32, MipmapsOption::NoMipmap, size.0, size.1)
+        .map_err(|e| ShaderError::Draw(format!("Could not create a {}x{} buffer render target: {:?}", size.0, size.1, e)))?;
+    texture.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+    Ok(texture)
+}
+
+/// The Cube A pass, rendering into a pair of half float cubemaps so it can read its own previous
+/// frame. Unlike buffers, its size doesn't follow the screen.
+struct CubePass {
+    program: Program,
+    bindings: Bindings,
+    targets: [Cubemap; 2],
+    current: usize,
+}
+
+impl CubePass {
+    fn output(&self) -> &Cubemap {
+        &self.targets[self.current]
+    }
+}
+
+fn create_cube_target<F>(display: &F) -> Result<Cubemap, ShaderError>
+    where F: Facade {
+    // Half floats, like on Shadertoy. Full floats would take 200 MB for the pair.
+    let cubemap = Cubemap::empty_with_format(display, UncompressedFloatFormat::F16F16F16F16, MipmapsOption::NoMipmap, CUBE_SIZE)
+        .map_err(|e| ShaderError::Draw(format!("Could not create cube render target: {:?}", e)))?;
+    for &layer in &CUBE_LAYERS {
+        cube_face_framebuffer(display, &cubemap, layer)?
+            .clear_color(0.0, 0.0, 0.0, 0.0);
+    }
+    Ok(cubemap)
+}
+
+fn cube_face_framebuffer<'a, F>(display: &F, cubemap: &'a Cubemap, layer: CubeLayer) -> Result<SimpleFrameBuffer<'a>, ShaderError>
+    where F: Facade {
+    SimpleFrameBuffer::new(display, cubemap.main_level().image(layer))
+        .map_err(|e| ShaderError::Draw(format!("Could not create cube face framebuffer: {:?}", e)))
+}
+
+/// Everything needed to draw a Shadertoy project besides the Image pass program: the Image
+/// pass's channel inputs and the buffer passes.
+pub struct Multipass {
+    image_bindings: Bindings,
+    buffers: Vec<Option<BufferPass>>,
+    cube: Option<CubePass>,
+    size: (u32, u32),
+}
+
+impl Multipass {
+    pub fn new<F>(display: &F, vs_src: &ShaderSource, project: &Project, size: (u32, u32)) -> Result<Self, ShaderError>
+        where F: Facade {
+        let image_bindings = load_bindings(display, &project.image.inputs)?;
+
+        let mut buffers = Vec::new();
+        for config in &project.buffers {
+            let buffer = match config {
+                Some(config) => Some(BufferPass {
+                    program: compile_pass(display, vs_src, config, project.common.as_ref())?,
+                    bindings: load_bindings(display, &config.inputs)?,
+                    targets: [create_target(display, size)?, create_target(display, size)?],
+                    current: 0,
+                }),
+                None => None,
+            };
+            buffers.push(buffer);
+        }
+
+        let cube = match &project.cube {
+            Some(config) => Some(CubePass {
+                program: compile_cube_pass(display, vs_src, config, project.common.as_ref())?,
+                bindings: load_bindings(display, &config.inputs)?,
+                targets: [create_cube_target(display)?, create_cube_target(display)?],
+                current: 0,
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            image_bindings,
+            buffers,
+            cube,
+            size,
+        })
+    }
+
+    /// A single pass project with no channel inputs.
+    pub fn empty() -> Self {
+        Self {
+            image_bindings: Default::default(),
+            buffers: Vec::new(),
+            cube: None,
+            size: (0, 0),
+        }
+    }
+
+    /// Recreate the buffer render targets if the screen size changed. Their contents are lost.
+    /// If creating them fails, the old ones are kept and the next call tries again.
+    pub fn resize<F>(&mut self, display: &F, size: (u32, u32)) -> Result<(), ShaderError>
+        where F: Facade {
+        if size == self.size {
+            return Ok(());
+        }
+        let mut targets = Vec::new();
+        for _ in self.buffers.iter().flatten() {
+            targets.push([create_target(display, size)?, create_target(display, size)?]);
+        }
+        for (buffer, targets) in self.buffers.iter_mut().flatten().zip(targets) {
+            buffer.targets = targets;
+            buffer.current = 0;
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// Advance audio inputs to `time`. They stay put while time is paused.
+    pub fn update_audio(&mut self, time: f32) {
+        let bindings = self.buffers.iter_mut()
+            .flatten()
+            .flat_map(|buffer| buffer.bindings.iter_mut())
+            .chain(self.cube.iter_mut().flat_map(|cube| cube.bindings.iter_mut()))
+            .chain(self.image_bindings.iter_mut());
+        for binding in bindings {
+            if let Some(Binding::Audio(audio)) = binding {
+                audio.update(time);
+            }
+        }
+    }
+
+    /// Render each buffer pass in order, then the Cube A pass. Passes see this frame's output of
+    /// earlier passes and last frame's output of themselves and later passes, like on Shadertoy.
+    pub fn render_buffers<F>(&mut self, display: &F, vertex_buffer: &VertexBuffer<Vertex>, index_buffer: &IndexBuffer<u8>, uniforms: &mut ShadertoyUniforms, keyboard: &Keyboard) -> Result<(), ShaderError>
+        where F: Facade {
+        for i in 0..self.buffers.len() {
+            if let Some(buffer) = &self.buffers[i] {
+                let channels = self.channel_values(&buffer.bindings, keyboard, uniforms);
+                let target = &buffer.targets[1 - buffer.current];
+                target.as_surface().draw(
+                    vertex_buffer,
+                    index_buffer,
+                    &buffer.program,
+                    &WithChannels { uniforms: &*uniforms, channels },
+                    &Default::default(),
+                ).map_err(|e| ShaderError::Draw(e.to_string()))?;
+            }
+            if let Some(buffer) = &mut self.buffers[i] {
+                buffer.current = 1 - buffer.current;
+            }
+        }
+
+        if let Some(cube) = &self.cube {
+            let channels = self.channel_values(&cube.bindings, keyboard, uniforms);
+            // Faces are square, whatever the screen's resolution.
+            let resolution = uniforms.resolution;
+            uniforms.resolution = [CUBE_SIZE as f32, CUBE_SIZE as f32, 1.0];
+            let target = &cube.targets[1 - cube.current];
+            for (face, &layer) in CUBE_LAYERS.iter().enumerate() {
+                let face_uniforms = CubeFaceUniforms {
+                    uniforms: &*uniforms,
+                    face: face as i32,
+                };
+                cube_face_framebuffer(display, target, layer)?
+                    .draw(
+                        vertex_buffer,
+                        index_buffer,
+                        &cube.program,
+                        &WithChannels { uniforms: &face_uniforms, channels },
+                        &Default::default(),
+                    )
+                    .map_err(|e| ShaderError::Draw(e.to_string()))?;
+            }
+            uniforms.resolution = resolution;
+        }
+        if let Some(cube) = &mut self.cube {
+            cube.current = 1 - cube.current;
+        }
+        Ok(())
+    }
+
+    /// Uniforms for drawing the Image pass.
+    pub fn image_uniforms<'a>(&'a self, uniforms: &'a mut ShadertoyUniforms, keyboard: &'a Keyboard) -> WithChannels<'a, ShadertoyUniforms> {
+        let channels = self.channel_values(&self.image_bindings, keyboard, uniforms);
+        WithChannels { uniforms: &*uniforms, channels }
+    }
+
+    /// Sampler values for a pass's channels. Also fills in the pass's iChannelResolution and
+    /// iChannelTime, which only media inputs advance, like on Shadertoy.
+    fn channel_values<'a>(&'a self, bindings: &'a Bindings, keyboard: &'a Keyboard, uniforms: &mut ShadertoyUniforms) -> [Option<UniformValue<'a>>; CHANNEL_COUNT] {
+        let mut values = [None; CHANNEL_COUNT];
+        uniforms.channel_resolution = [[0.0; 3]; CHANNEL_COUNT];
+        uniforms.channel_time = [0.0; CHANNEL_COUNT];
+        for (i, binding) in bindings.iter().enumerate() {
+            let (texture, sampler) = match binding {
+                Some(Binding::Keyboard) => {
+                    values[i] = Some(keyboard.as_uniform_value());
+                    uniforms.channel_resolution[i] = keyboard.resolution();
+                    continue;
+                }
+                Some(Binding::Audio(audio)) => {
+                    values[i] = Some(audio.as_uniform_value());
+                    uniforms.channel_resolution[i] = audio.resolution();
+                    uniforms.channel_time[i] = audio.position();
+                    continue;
+                }
+                Some(Binding::Cubemap(cubemap, sampler)) => {
+                    values[i] = Some(UniformValue::Cubemap(cubemap, Some(*sampler)));
+                    let size = cubemap.width() as f32;
+                    uniforms.channel_resolution[i] = [size, size, 1.0];
+                    continue;
+                }
+                Some(Binding::CubeBuffer) => {
+                    if let Some(cube) = &self.cube {
+                        values[i] = Some(UniformValue::Cubemap(cube.output(), Some(buffer_sampler())));
+                        uniforms.channel_resolution[i] = [CUBE_SIZE as f32, CUBE_SIZE as f32, 1.0];
+                    }
+                    continue;
+                }
+                Some(Binding::Texture(texture, sampler)) => {
+                    let (width, height) = texture.dimensions();
+                    values[i] = Some(texture.as_uniform_value(*sampler));
+                    uniforms.channel_resolution[i] = [width as f32, height as f32, 1.0];
+                    continue;
+                }
+                Some(Binding::Buffer(b)) => match self.buffers.get(*b) {
+                    Some(Some(buffer)) => (buffer.output(), buffer_sampler()),
+                    _ => continue,
+                },
+                None => continue,
+            };
+            let (width, height) = texture.dimensions();
+            values[i] = Some(UniformValue::Texture2d(texture, Some(sampler)));
+            uniforms.channel_resolution[i] = [width as f32, height as f32, 1.0];
+        }
+        values
+    }
+}
+
+// Shadertoy's defaults for buffer inputs: linear and clamped.
+fn buffer_sampler() -> SamplerBehavior {
+    SamplerBehavior {
+        wrap_function: (SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp),
+        minify_filter: MinifySamplerFilter::Linear,
+        magnify_filter: MagnifySamplerFilter::Linear,
+        ..Default::default()
+    }
+}
+
+/// The Shadertoy uniforms plus which face of the cubemap the Cube A pass is drawing.
+struct CubeFaceUniforms<'a> {
+    uniforms: &'a ShadertoyUniforms,
+    face: i32,
+}
+
+impl<'a> GliumUniforms for CubeFaceUniforms<'a> {
+    fn visit_values<'uniform, F>(&'uniform self, mut output: F)
+        where F: FnMut(&str, UniformValue<'uniform>) {
+        self.uniforms.visit_values(&mut output);
+        output("iCubeFace", self.face.as_uniform_value());
+    }
+}
+
+/// Adds the iChannel samplers to another set of uniforms.
+pub struct WithChannels<'a, U> {
+    pub uniforms: &'a U,
+    pub channels: [Option<UniformValue<'a>>; CHANNEL_COUNT],
+}
+
+impl<'a, U> GliumUniforms for WithChannels<'a, U>
+    where U: GliumUniforms {
+    fn visit_values<'uniform, F>(&'uniform self, mut output: F)
+        where F: FnMut(&str, UniformValue<'uniform>) {
+        self.uniforms.visit_values(&mut output);
+        for (name, channel) in CHANNEL_NAMES.iter().zip(&self.channels) {
+            if let Some(channel) = channel {
+                output(name, *channel);
+            }
+        }
+    }
+}
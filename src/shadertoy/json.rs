@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::ShaderError;
+use crate::texture::{CubemapImages, Filter, TextureOptions, Wrap};
+use super::{BUFFER_COUNT, ChannelInput, ChannelInputs, PassConfig, PassSource, Project};
+
+#[derive(Deserialize)]
+struct Shader {
+    renderpass: Vec<RenderPass>,
+}
+
+#[derive(Deserialize)]
+struct RenderPass {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    code: String,
+    #[serde(default)]
+    inputs: Vec<Input>,
+    #[serde(default)]
+    outputs: Vec<Output>,
+}
+
+#[derive(Deserialize)]
+struct Input {
+    id: JsonValue,
+    // Older exports use "filepath" and "type".
+    #[serde(alias = "filepath", default)]
+    src: String,
+    #[serde(alias = "type")]
+    ctype: String,
+    channel: usize,
+    sampler: Option<Sampler>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+struct Sampler {
+    #[serde(default)]
+    filter: String,
+    #[serde(default)]
+    wrap: String,
+    #[serde(default)]
+    vflip: String,
+    #[serde(default)]
+    srgb: String,
+}
+
+impl Sampler {
+    fn texture_options(&self) -> TextureOptions {
+        let defaults = TextureOptions::default();
+        TextureOptions {
+            filter: Filter::parse(&self.filter).unwrap_or(defaults.filter),
+            wrap: Wrap::parse(&self.wrap).unwrap_or(defaults.wrap),
+            vflip: self.vflip != "false",
+            srgb: self.srgb == "true",
+        }
+    }
+}
+
+/// Build a project from a Shadertoy JSON export. The API wraps the shader in a "Shader" object
+/// and browser extensions export an array of shaders, so both are accepted along with a bare
+/// shader object. Since there's no network access, texture and music inputs are looked up by file
+/// name in `textures_dir`.
+pub fn load(json_path: &Path, textures_dir: &Path) -> Result<Project, ShaderError> {
+    let json_error = |message: String| ShaderError::Json(json_path.to_path_buf(), message);
+
+    let src = crate::read_shader(&json_path)?;
+    let document: JsonValue = serde_json::from_str(&src)
+        .map_err(|e| json_error(e.to_string()))?;
+    let shader = match document {
+        JsonValue::Object(mut object) if object.contains_key("Shader") => object.remove("Shader"),
+        JsonValue::Array(mut array) if !array.is_empty() => Some(array.swap_remove(0)),
+        document => Some(document),
+    };
+    let shader: Shader = shader
+        .ok_or_else(|| json_error("No shader found".into()))
+        .and_then(|shader| serde_json::from_value(shader).map_err(|e| json_error(e.to_string())))?;
+
+    // Buffers are referenced by the ids of their outputs.
+    let mut buffer_ids = Vec::new();
+    let cube_ids: Vec<JsonValue> = shader.renderpass.iter()
+        .filter(|p| p.kind == "cubemap")
+        .flat_map(|p| p.outputs.iter().map(|output| output.id.clone()))
+        .collect();
+    let mut next_buffer = 0;
+    for pass in shader.renderpass.iter().filter(|p| p.kind == "buffer") {
+        let index = buffer_index(&pass.name).unwrap_or(next_buffer);
+        next_buffer = index + 1;
+        for output in &pass.outputs {
+            buffer_ids.push((output.id.clone(), index));
+        }
+    }
+
+    let mut image = None;
+    let mut buffers: [Option<PassConfig>; BUFFER_COUNT] = Default::default();
+    let mut cube = None;
+    let mut sound = None;
+    let mut common = None;
+    let mut next_buffer = 0;
+    for pass in &shader.renderpass {
+        let label = format!("{} [{}]", json_path.display(), pass.name);
+        let source = PassSource::Embedded(PathBuf::from(label), pass.code.clone());
+        match pass.kind.as_str() {
+            "common" => common = Some(source),
+            "image" => image = Some(PassConfig {
+                source,
+                inputs: pass_inputs(pass, &buffer_ids, &cube_ids, textures_dir),
+            }),
+            "cubemap" => cube = Some(PassConfig {
+                source,
+                inputs: pass_inputs(pass, &buffer_ids, &cube_ids, textures_dir),
+            }),
+            "sound" => sound = Some(PassConfig {
+                source,
+                inputs: pass_inputs(pass, &buffer_ids, &cube_ids, textures_dir),
+            }),
+            "buffer" => {
+                let index = buffer_index(&pass.name).unwrap_or(next_buffer);
+                next_buffer = index + 1;
+                if index < BUFFER_COUNT {
+                    buffers[index] = Some(PassConfig {
+                        source,
+                        inputs: pass_inputs(pass, &buffer_ids, &cube_ids, textures_dir),
+                    });
+                }
+            }
+            kind => eprintln!("Skipping unsupported {} pass \"{}\"", kind, pass.name),
+        }
+    }
+
+    let image = image.ok_or_else(|| json_error("No image pass found".into()))?;
+    Ok(Project {
+        image,
+        buffers,
+        cube,
+        sound,
+        common,
+        config_path: Some(json_path.to_path_buf()),
+    })
+}
+
+/// Buffer passes are named "Buffer A" to "Buffer D".
+fn buffer_index(name: &str) -> Option<usize> {
+    match name.trim().to_lowercase().as_str() {
+        "buffer a" => Some(0),
+        "buffer b" => Some(1),
+        "buffer c" => Some(2),
+        "buffer d" => Some(3),
+        _ => None,
+    }
+}
+
+fn pass_inputs(pass: &RenderPass, buffer_ids: &[(JsonValue, usize)], cube_ids: &[JsonValue], textures_dir: &Path) -> ChannelInputs {
+    let mut inputs = ChannelInputs::default();
+    for input in &pass.inputs {
+        if input.channel >= inputs.len() {
+            continue;
+        }
+        let channel_input = match input.ctype.as_str() {
+            "texture" => {
+                let file_name = Path::new(&input.src).file_name().unwrap_or_default();
+                let options = input.sampler.as_ref()
+                    .map(Sampler::texture_options)
+                    .unwrap_or_default();
+                ChannelInput::Texture(textures_dir.join(file_name), options)
+            }
+            "music" | "musicstream" => {
+                let file_name = Path::new(&input.src).file_name().unwrap_or_default();
+                ChannelInput::Audio(textures_dir.join(file_name))
+            }
+            "cubemap" if cube_ids.contains(&input.id) => ChannelInput::CubeBuffer,
+            "cubemap" => {
+                let options = input.sampler.as_ref()
+                    .map(Sampler::texture_options)
+                    .unwrap_or_default();
+                ChannelInput::Cubemap(cubemap_faces(&input.src, textures_dir), options)
+            }
+            "keyboard" => ChannelInput::Keyboard,
+            "buffer" => match buffer_ids.iter().find(|(id, _)| id == &input.id) {
+                Some(&(_, index)) => ChannelInput::Buffer(index),
+                None => {
+                    eprintln!("Pass \"{}\" reads from unknown buffer {}", pass.name, input.id);
+                    continue;
+                }
+            },
+            ctype => {
+                eprintln!("Skipping unsupported {} input on channel {} of pass \"{}\"", ctype, input.channel, pass.name);
+                continue;
+            }
+        };
+        inputs[input.channel] = Some(channel_input);
+    }
+    inputs
+}
+
+/// Shadertoy cubemaps are referenced by their first face. The others have the same name with a
+/// "_1" to "_5" suffix.
+fn cubemap_faces(src: &str, textures_dir: &Path) -> CubemapImages {
+    let first = textures_dir.join(Path::new(src).file_name().unwrap_or_default());
+    let mut faces: [PathBuf; 6] = Default::default();
+    for (i, face) in faces.iter_mut().enumerate() {
+        *face = if i == 0 {
+            first.clone()
+        } else {
+            let stem = first.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = first.extension().unwrap_or_default().to_string_lossy();
+            first.with_file_name(format!("{}_{}.{}", stem, i, extension))
+        };
+    }
+    CubemapImages::Faces(faces)
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use glium::{Surface, Texture2d};
+use glium::backend::Facade;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use glium::uniforms::{AsUniformValue, Uniforms as GliumUniforms, UniformValue};
+
+use crate::{FragmentShader, ShaderError, ShadertoyUniforms, Uniforms, create_program, create_quad};
+use crate::shadertoy::{Project, SOUND_BLOCK_WIDTH};
+
+/// Rows of samples rendered per draw call. Shadertoy renders 512x512 blocks too.
+const SOUND_BLOCK_HEIGHT: u32 = 512;
+
+/// What to render a Sound pass to.
+#[derive(Clone, Copy, Debug)]
+pub struct SoundSettings {
+    /// Length of the audio, in seconds.
+    pub duration: f32,
+    /// Samples per second, passed to the shader as iSampleRate.
+    pub sample_rate: u32,
+}
+
+/// The Shadertoy uniforms plus the index of the first sample in the block being rendered.
+struct SoundUniforms<'a> {
+    uniforms: &'a ShadertoyUniforms,
+    block_offset: i32,
+}
+
+impl<'a> GliumUniforms for SoundUniforms<'a> {
+    fn visit_values<'uniform, F>(&'uniform self, mut output: F)
+        where F: FnMut(&str, UniformValue<'uniform>) {
+        self.uniforms.visit_values(&mut output);
+        output("iSoundBlockOffset", self.block_offset.as_uniform_value());
+    }
+}
+
+/// Render a project's Sound pass to a 16-bit stereo WAV file. Samples are computed on the GPU a
+/// block at a time into a float texture and read back, so long durations don't need a huge
+/// target.
+pub fn render_wav<F>(display: &F, vs_path: &Path, project: &Project, settings: &SoundSettings, wav_path: &Path) -> Result<(), ShaderError>
+    where F: Facade {
+    let output_error = |message: String| ShaderError::Output(wav_path.to_path_buf(), message);
+
+    let (program, uniforms) = create_program(display, vs_path, FragmentShader::ShadertoySound(project))?;
+    let mut uniforms = match uniforms {
+        Uniforms::Shadertoy(uniforms) => uniforms,
+        Uniforms::Freeform(_) => unreachable!("Sound passes always use Shadertoy uniforms"),
+    };
+    uniforms.sample_rate = settings.sample_rate as f32;
+    uniforms.resolution = [SOUND_BLOCK_WIDTH as f32, SOUND_BLOCK_HEIGHT as f32, 1.0];
+    if project.sound.iter().any(|sound| sound.inputs.iter().any(Option::is_some)) {
+        eprintln!("Channel inputs are not supported for the Sound pass, ignoring them");
+    }
+
+    let (vertex_buffer, index_buffer) = create_quad(display);
+    let target = Texture2d::empty_with_format(display, UncompressedFloatFormat::F32F32F32F32, MipmapsOption::NoMipmap, SOUND_BLOCK_WIDTH, SOUND_BLOCK_HEIGHT)
+        .map_err(|e| ShaderError::Draw(format!("Could not create sound render target: {:?}", e)))?;
+
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: settings.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(wav_path, spec)
+        .map_err(|e| output_error(e.to_string()))?;
+
+    let total_samples = (settings.duration.max(0.0) * settings.sample_rate as f32).round() as usize;
+    let block_size = (SOUND_BLOCK_WIDTH * SOUND_BLOCK_HEIGHT) as usize;
+    let mut written = 0;
+    while written < total_samples {
+        let sound_uniforms = SoundUniforms {
+            uniforms: &uniforms,
+            block_offset: written as i32,
+        };
+        target.as_surface().draw(
+            &vertex_buffer,
+            &index_buffer,
+            &program,
+            &sound_uniforms,
+            &Default::default(),
+        ).map_err(|e| ShaderError::Draw(e.to_string()))?;
+
+        // NOTE: glium only has a checked read for 8-bit pixels. The target is RGBA32F, so reading
+        // it back as f32 tuples is sound.
+        let rows: Vec<Vec<(f32, f32, f32, f32)>> = unsafe { target.unchecked_read() };
+        let samples = rows.iter()
+            .flatten()
+            .take((total_samples - written).min(block_size));
+        for &(left, right, _, _) in samples {
+            for &value in &[left, right] {
+                writer.write_sample(to_pcm(value))
+                    .map_err(|e| output_error(e.to_string()))?;
+            }
+            written += 1;
+        }
+    }
+
+    writer.finalize()
+        .map_err(|e| output_error(e.to_string()))
+}
+
+fn to_pcm(value: f32) -> i16 {
+    // NaNs become silence rather than a full scale click.
+    if value.is_nan() {
+        return 0;
+    }
+    (value.max(-1.0).min(1.0) * i16::max_value() as f32) as i16
+}
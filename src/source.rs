@@ -0,0 +1,369 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ShaderError;
+
+/// A contiguous run of generated lines that came from one place.
+#[derive(Debug)]
+struct Chunk {
+    // First line of the chunk in the generated source, 1-based.
+    start: u32,
+    len: u32,
+    // None for lines generated by shade-storm itself.
+    file: Option<PathBuf>,
+    // Line in `file` that `start` corresponds to.
+    file_start: u32,
+}
+
+/// GLSL source assembled from several pieces, remembering where each line came from so driver
+/// diagnostics can be reported against the user's files.
+#[derive(Debug)]
+pub struct ShaderSource {
+    text: String,
+    chunks: Vec<Chunk>,
+    lines: u32,
+    // Files pulled in with #include, in the order they were first included.
+    includes: Vec<PathBuf>,
+}
+
+impl ShaderSource {
+    pub fn new() -> Self {
+        Self {
+            text: String::new(),
+            chunks: Vec::new(),
+            lines: 0,
+            includes: Vec::new(),
+        }
+    }
+
+    /// Source that consists of a single file, starting at its first line.
+    pub fn from_file(path: &Path, text: &str) -> Self {
+        let mut source = Self::new();
+        source.push_file(path, text, 1);
+        source
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Append lines that shade-storm generated, e.g. the Shadertoy wrapper.
+    pub fn push_generated(&mut self, text: &str) {
+        self.push(None, text, 1);
+    }
+
+    /// Append lines taken from `path`, where the first line of `text` is line `first_line` of
+    /// the file.
+    pub fn push_file(&mut self, path: &Path, text: &str, first_line: u32) {
+        self.push(Some(path.to_path_buf()), text, first_line);
+    }
+
+    /// Like `push_file`, but replaces `#include "file"` lines with the lines of that file, found
+    /// relative to the including file. Each file is only included once, so helpers don't need
+    /// include guards, and an include cycle is an error.
+    pub fn push_file_with_includes(&mut self, path: &Path, text: &str, first_line: u32) -> Result<(), ShaderError> {
+        let mut stack = vec![path.to_path_buf()];
+        self.push_included(path, text, first_line, &mut stack)
+    }
+
+    // `stack` holds the files currently being included, outermost first.
+    fn push_included(&mut self, path: &Path, text: &str, first_line: u32, stack: &mut Vec<PathBuf>) -> Result<(), ShaderError> {
+        // Lines since the last #include, pushed as one chunk.
+        let mut pending = String::new();
+        let mut pending_start = first_line;
+        // Whether the current line starts inside a /* */ comment.
+        let mut in_comment = false;
+        for (i, line) in text.lines().enumerate() {
+            let line_number = first_line + i as u32;
+            let code = strip_block_comments(line, &mut in_comment);
+            let target = match parse_include(&code) {
+                Some(target) => target,
+                None => {
+                    pending.push_str(line);
+                    pending.push('\n');
+                    continue;
+                }
+            };
+            self.push_file(path, &pending, pending_start);
+            pending.clear();
+            pending_start = line_number + 1;
+
+            let include_error = |message: String| ShaderError::Include(path.to_path_buf(), line_number, message);
+            let dir = path.parent()
+                .unwrap_or_else(|| Path::new(""));
+            // Canonical paths so the same file reached two ways is recognized.
+            let target = fs::canonicalize(dir.join(target))
+                .map_err(|e| include_error(format!("Could not find \"{}\": {}", target, e)))?;
+            if stack.contains(&target) {
+                let cycle: Vec<String> = stack.iter()
+                    .chain(Some(&target))
+                    .map(|p| format!("\"{}\"", p.display()))
+                    .collect();
+                return Err(include_error(format!("Include cycle: {}", cycle.join(" -> "))));
+            }
+            if self.includes.contains(&target) {
+                continue;
+            }
+            let included_text = fs::read_to_string(&target)
+                .map_err(|e| include_error(format!("Could not read \"{}\": {}", target.display(), e)))?;
+
+            self.includes.push(target.clone());
+            stack.push(target.clone());
+            self.push_included(&target, &included_text, 1, stack)?;
+            stack.pop();
+        }
+        self.push_file(path, &pending, pending_start);
+        Ok(())
+    }
+
+    fn push(&mut self, file: Option<PathBuf>, text: &str, file_start: u32) {
+        if text.is_empty() {
+            return;
+        }
+        self.text.push_str(text);
+        if !text.ends_with('\n') {
+            self.text.push('\n');
+        }
+        let len = text.lines().count() as u32;
+        self.chunks.push(Chunk {
+            start: self.lines + 1,
+            len,
+            file,
+            file_start,
+        });
+        self.lines += len;
+    }
+
+    /// Map a line of the generated source back to the file and line it came from. Generated
+    /// lines map to no file.
+    pub fn lookup(&self, line: u32) -> (Option<&Path>, u32) {
+        for chunk in &self.chunks {
+            if line >= chunk.start && line < chunk.start + chunk.len {
+                let file = chunk.file.as_ref().map(|p| p.as_path());
+                return (file, chunk.file_start + (line - chunk.start));
+            }
+        }
+        (None, line)
+    }
+}
+
+/// The file named by an `#include "file"` line, if it is one.
+fn parse_include(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if !line.starts_with('#') {
+        return None;
+    }
+    let rest = line[1..].trim_start();
+    if !rest.starts_with("include") {
+        return None;
+    }
+    let rest = rest["include".len()..].trim_start();
+    if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+        Some(&rest[1..rest.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// The parts of `line` outside /* */ comments, so commented out #include lines are left alone.
+/// `in_comment` says whether the line starts inside a comment, and is updated for the next line.
+fn strip_block_comments(line: &str, in_comment: &mut bool) -> String {
+    let mut code = String::new();
+    let mut rest = line;
+    loop {
+        if *in_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    *in_comment = false;
+                    rest = &rest[end + 2..];
+                    code.push(' ');
+                }
+                None => return code,
+            }
+        } else {
+            // A /* after a // is commented out itself.
+            let line_comment = rest.find("//");
+            match rest.find("/*") {
+                Some(start) if line_comment.map_or(true, |comment| start < comment) => {
+                    code.push_str(&rest[..start]);
+                    *in_comment = true;
+                    rest = &rest[start + 2..];
+                }
+                _ => {
+                    code.push_str(rest);
+                    return code;
+                }
+            }
+        }
+    }
+}
+
+/// Files that `text`, lines of `path` starting at `first_line`, includes, directly or not.
+/// Errors are left for compiling the shader to report, so this returns whatever was found before
+/// one.
+pub fn dependencies(path: &Path, text: &str, first_line: u32) -> Vec<PathBuf> {
+    let mut source = ShaderSource::new();
+    let _ = source.push_file_with_includes(path, text, first_line);
+    source.includes
+}
+
+/// A single message reported by the driver or by shade-storm while building a shader.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: {}", file.display(), line, self.message),
+            (Some(file), None) => write!(f, "{}: {}", file.display(), self.message),
+            (None, Some(line)) => write!(f, "<generated>:{}: {}", line, self.message),
+            (None, None) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Split a driver info log into diagnostics, mapping line numbers through `source`.
+pub fn parse_log(log: &str, source: &ShaderSource) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for log_line in log.lines() {
+        let log_line = log_line.trim();
+        if log_line.is_empty() {
+            continue;
+        }
+        let diagnostic = match parse_log_line(log_line) {
+            Some((line, message)) => {
+                let (file, line) = source.lookup(line);
+                Diagnostic {
+                    file: file.map(|p| p.to_path_buf()),
+                    line: Some(line),
+                    message,
+                }
+            }
+            None => Diagnostic {
+                file: None,
+                line: None,
+                message: log_line.into(),
+            },
+        };
+        diagnostics.push(diagnostic);
+    }
+    diagnostics
+}
+
+/// Extract the line number and message from one line of a driver info log. Understands the
+/// formats used by Mesa ("0:12(5): error: ..."), NVIDIA ("0(12) : error C0000: ...") and
+/// AMD/Intel/Apple ("ERROR: 0:12: ...").
+fn parse_log_line(log_line: &str) -> Option<(u32, String)> {
+    let mut rest = log_line;
+    let mut severity = None;
+    for &(prefix, name) in &[("ERROR: ", "error"), ("WARNING: ", "warning")] {
+        if rest.starts_with(prefix) {
+            severity = Some(name);
+            rest = &rest[prefix.len()..];
+        }
+    }
+
+    // Skip the source string index.
+    let index_len = rest.find(|c: char| !c.is_ascii_digit())?;
+    if index_len == 0 {
+        return None;
+    }
+    rest = &rest[index_len..];
+    if rest.starts_with(':') || rest.starts_with('(') {
+        rest = &rest[1..];
+    } else {
+        return None;
+    }
+
+    let line_len = rest.find(|c: char| !c.is_ascii_digit())?;
+    let line = rest[..line_len].parse().ok()?;
+    rest = &rest[line_len..];
+
+    // Skip anything else in the location, like a column or a closing paren.
+    let message_start = rest.find(": ")?;
+    let message = rest[message_start + 2..].trim();
+    let message = match severity {
+        Some(severity) => format!("{}: {}", severity, message),
+        None => message.into(),
+    };
+    Some((line, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mesa_log_line() {
+        assert_eq!(
+            parse_log_line("0:12(3): error: `foo' undeclared"),
+            Some((12, "error: `foo' undeclared".into())),
+        );
+    }
+
+    #[test]
+    fn parse_nvidia_log_line() {
+        assert_eq!(
+            parse_log_line("0(12) : error C1008: undefined variable \"foo\""),
+            Some((12, "error C1008: undefined variable \"foo\"".into())),
+        );
+    }
+
+    #[test]
+    fn parse_amd_log_line() {
+        assert_eq!(
+            parse_log_line("ERROR: 0:12: 'foo' : undeclared identifier"),
+            Some((12, "error: 'foo' : undeclared identifier".into())),
+        );
+    }
+
+    #[test]
+    fn parse_other_log_line() {
+        assert_eq!(parse_log_line("Compilation failed."), None);
+    }
+
+    #[test]
+    fn includes_in_block_comments_are_skipped() {
+        let mut in_comment = false;
+        let lines = ["/* #include \"a.glsl\"", "#include \"b.glsl\"", "*/ #include \"c.glsl\""];
+        let includes: Vec<Option<String>> = lines.iter()
+            .map(|line| parse_include(&strip_block_comments(line, &mut in_comment)).map(String::from))
+            .collect();
+        assert_eq!(includes, vec![None, None, Some("c.glsl".into())]);
+        assert!(!in_comment);
+    }
+
+    #[test]
+    fn lookup_maps_chunks_to_files() {
+        let dir = std::env::temp_dir().join(format!("shade-storm-source-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let shader = dir.join("shader.frag");
+        let common = dir.join("common.glsl");
+        fs::write(&common, "float a;\nfloat b;\n").unwrap();
+        let shader_text = "// TOML\n#include \"common.glsl\"\nvoid main() {\n}\n";
+        fs::write(&shader, shader_text).unwrap();
+
+        let mut source = ShaderSource::new();
+        source.push_generated("#version 330\nuniform float time;\n");
+        // As if the shader's first line had been cut off.
+        source.push_file_with_includes(&shader, &shader_text[8..], 2).unwrap();
+        let common = fs::canonicalize(&common).unwrap();
+
+        // Generated lines.
+        assert_eq!(source.lookup(1), (None, 1));
+        assert_eq!(source.lookup(2), (None, 2));
+        // The included file replaces the #include line on line 2 of the shader.
+        assert_eq!(source.lookup(3), (Some(common.as_path()), 1));
+        assert_eq!(source.lookup(4), (Some(common.as_path()), 2));
+        assert_eq!(source.lookup(5), (Some(shader.as_path()), 3));
+        assert_eq!(source.lookup(6), (Some(shader.as_path()), 4));
+        assert_eq!(source.text().lines().count(), 6);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
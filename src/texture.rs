@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+
+use glium::{Surface, Texture2d};
+use glium::texture::SrgbTexture2d;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{CubeLayer, Cubemap, MipmapsOption, RawImage2d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerBehavior, SamplerWrapFunction, UniformValue};
+use image::GenericImageView;
+
+use crate::ShaderError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+    Mipmap,
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "nearest" => Some(Filter::Nearest),
+            "linear" => Some(Filter::Linear),
+            "mipmap" => Some(Filter::Mipmap),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl Wrap {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "repeat" => Some(Wrap::Repeat),
+            "clamp" => Some(Wrap::Clamp),
+            "mirror" => Some(Wrap::Mirror),
+            _ => None,
+        }
+    }
+}
+
+/// How an image file is loaded and sampled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextureOptions {
+    pub filter: Filter,
+    pub wrap: Wrap,
+    /// Flip the image so its top row ends up at the top of texture space, like Shadertoy does.
+    pub vflip: bool,
+    /// Treat the image as sRGB so it's converted to linear when sampled.
+    pub srgb: bool,
+}
+
+impl Default for TextureOptions {
+    // Shadertoy's defaults for textures: mipmapped, repeating and flipped.
+    fn default() -> Self {
+        Self {
+            filter: Filter::Mipmap,
+            wrap: Wrap::Repeat,
+            vflip: true,
+            srgb: false,
+        }
+    }
+}
+
+impl TextureOptions {
+    pub fn sampler(&self) -> SamplerBehavior {
+        let wrap = match self.wrap {
+            Wrap::Repeat => SamplerWrapFunction::Repeat,
+            Wrap::Clamp => SamplerWrapFunction::Clamp,
+            Wrap::Mirror => SamplerWrapFunction::Mirror,
+        };
+        let (minify_filter, magnify_filter) = match self.filter {
+            Filter::Nearest => (MinifySamplerFilter::Nearest, MagnifySamplerFilter::Nearest),
+            Filter::Linear => (MinifySamplerFilter::Linear, MagnifySamplerFilter::Linear),
+            Filter::Mipmap => (MinifySamplerFilter::LinearMipmapLinear, MagnifySamplerFilter::Linear),
+        };
+        SamplerBehavior {
+            wrap_function: (wrap, wrap, wrap),
+            minify_filter,
+            magnify_filter,
+            ..Default::default()
+        }
+    }
+
+    /// Like `sampler`, for cubemaps. They don't have mipmaps, so that filter becomes linear.
+    pub fn cubemap_sampler(&self) -> SamplerBehavior {
+        let filter = match self.filter {
+            Filter::Mipmap => Filter::Linear,
+            filter => filter,
+        };
+        TextureOptions { filter, ..*self }.sampler()
+    }
+}
+
+/// An image loaded as a texture. sRGB images need a texture type of their own in glium.
+#[derive(Debug)]
+pub enum ImageTexture {
+    Linear(Texture2d),
+    Srgb(SrgbTexture2d),
+}
+
+impl ImageTexture {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            ImageTexture::Linear(texture) => texture.dimensions(),
+            ImageTexture::Srgb(texture) => texture.dimensions(),
+        }
+    }
+
+    pub fn as_uniform_value(&self, sampler: SamplerBehavior) -> UniformValue {
+        match self {
+            ImageTexture::Linear(texture) => UniformValue::Texture2d(texture, Some(sampler)),
+            ImageTexture::Srgb(texture) => UniformValue::SrgbTexture2d(texture, Some(sampler)),
+        }
+    }
+}
+
+pub fn load_texture<F>(display: &F, path: &Path, options: &TextureOptions) -> Result<ImageTexture, ShaderError>
+    where F: Facade {
+    let image = image::open(path)
+        .map_err(|e| ShaderError::Texture(path.to_path_buf(), e.to_string()))?
+        .to_rgba();
+    let dimensions = image.dimensions();
+    // Images are stored top row first, but GL expects the bottom row first.
+    let raw = if options.vflip {
+        RawImage2d::from_raw_rgba_reversed(&image.into_raw(), dimensions)
+    } else {
+        RawImage2d::from_raw_rgba(image.into_raw(), dimensions)
+    };
+    let mipmaps = match options.filter {
+        Filter::Mipmap => MipmapsOption::AutoGeneratedMipmaps,
+        _ => MipmapsOption::NoMipmap,
+    };
+    let texture = if options.srgb {
+        SrgbTexture2d::with_mipmaps(display, raw, mipmaps)
+            .map(ImageTexture::Srgb)
+            .map_err(|e| format!("{:?}", e))
+    } else {
+        Texture2d::with_mipmaps(display, raw, mipmaps)
+            .map(ImageTexture::Linear)
+            .map_err(|e| format!("{:?}", e))
+    };
+    texture.map_err(|message| ShaderError::Texture(path.to_path_buf(), message))
+}
+
+/// Cubemap faces in the order GL numbers them.
+pub const CUBE_LAYERS: [CubeLayer; 6] = [
+    CubeLayer::PositiveX,
+    CubeLayer::NegativeX,
+    CubeLayer::PositiveY,
+    CubeLayer::NegativeY,
+    CubeLayer::PositiveZ,
+    CubeLayer::NegativeZ,
+];
+
+/// The image files a cubemap is loaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CubemapImages {
+    /// One image per face, in +X, -X, +Y, -Y, +Z, -Z order.
+    Faces([PathBuf; 6]),
+    /// A single image with the faces laid out in a horizontal (4x3) or vertical (3x4) cross.
+    Cross(PathBuf),
+}
+
+/// Load a cubemap. Faces are used as is, top row first, which is how GL expects cubemap faces.
+/// Cubemaps are loaded without mipmaps, see `TextureOptions::cubemap_sampler`.
+pub fn load_cubemap<F>(display: &F, images: &CubemapImages) -> Result<Cubemap, ShaderError>
+    where F: Facade {
+    let open = |path: &Path| image::open(path)
+        .map_err(|e| ShaderError::Texture(path.to_path_buf(), e.to_string()));
+
+    let (path, faces) = match images {
+        CubemapImages::Faces(paths) => {
+            let faces = paths.iter()
+                .map(|path| open(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            (&paths[0], faces)
+        }
+        CubemapImages::Cross(path) => (path, split_cross(path, open(path)?)?),
+    };
+    let texture_error = |message: String| ShaderError::Texture(path.to_path_buf(), message);
+
+    let size = faces[0].width();
+    if faces.iter().any(|face| face.width() != size || face.height() != size) {
+        return Err(texture_error("Cubemap faces must be square and all the same size".into()));
+    }
+
+    let cubemap = Cubemap::empty_with_format(display, UncompressedFloatFormat::U8U8U8U8, MipmapsOption::NoMipmap, size)
+        .map_err(|e| texture_error(format!("{:?}", e)))?;
+    for (layer, face) in CUBE_LAYERS.iter().zip(faces) {
+        let raw = RawImage2d::from_raw_rgba(face.to_rgba().into_raw(), (size, size));
+        let texture = Texture2d::new(display, raw)
+            .map_err(|e| texture_error(format!("{:?}", e)))?;
+        let target = SimpleFrameBuffer::new(display, cubemap.main_level().image(*layer))
+            .map_err(|e| texture_error(format!("{:?}", e)))?;
+        texture.as_surface().fill(&target, MagnifySamplerFilter::Nearest);
+    }
+    Ok(cubemap)
+}
+
+/// Cut the six faces out of a cross layout, in +X, -X, +Y, -Y, +Z, -Z order.
+fn split_cross(path: &Path, mut image: image::DynamicImage) -> Result<Vec<image::DynamicImage>, ShaderError> {
+    let (width, height) = (image.width(), image.height());
+    // Cells of each face, as (column, row) from the top left.
+    let (size, cells, vertical) = if width * 3 == height * 4 {
+        (width / 4, [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (3, 1)], false)
+    } else if width * 4 == height * 3 {
+        (width / 3, [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (1, 3)], true)
+    } else {
+        let message = format!("{}x{} is not a 4x3 or 3x4 cross layout", width, height);
+        return Err(ShaderError::Texture(path.to_path_buf(), message));
+    };
+    let mut faces: Vec<_> = cells.iter()
+        .map(|&(column, row)| image.crop(column * size, row * size, size, size))
+        .collect();
+    // The vertical cross has -Z hanging below -Y, so it's upside down.
+    if vertical {
+        faces[5] = faces[5].rotate180();
+    }
+    Ok(faces)
+}
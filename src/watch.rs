@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// The files a shader was built from, watched for changes.
+///
+/// Watching a file directly stops working once an editor saves it atomically by writing a new
+/// file and renaming it over the old one, so the directories holding the files are watched
+/// instead, non-recursively, and events for anything else in them are ignored.
+pub struct FileSet {
+    watcher: RecommendedWatcher,
+    rx: Receiver<DebouncedEvent>,
+    paths: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+}
+
+impl FileSet {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        let watcher = notify::watcher(tx, Duration::from_millis(500))
+            .expect("Could not create file watcher");
+        Self {
+            watcher,
+            rx,
+            paths: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Watch exactly `paths` from now on.
+    pub fn set_paths(&mut self, paths: Vec<PathBuf>) {
+        // notify reports canonical paths.
+        let mut paths: Vec<PathBuf> = paths.into_iter()
+            .map(|path| fs::canonicalize(&path).unwrap_or(path))
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let mut dirs: Vec<PathBuf> = paths.iter()
+            .filter_map(|path| path.parent())
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+
+        for dir in &self.dirs {
+            if !dirs.contains(dir) {
+                // The directory may be gone already, which unwatches it anyway.
+                let _ = self.watcher.unwatch(dir);
+            }
+        }
+        for dir in &dirs {
+            if !self.dirs.contains(dir) {
+                if let Err(e) = self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    eprintln!("Could not watch \"{}\": {}", dir.display(), e);
+                }
+            }
+        }
+
+        self.paths = paths;
+        self.dirs = dirs;
+    }
+
+    /// Watch `paths` as well as the files already in the set.
+    pub fn add_paths<I>(&mut self, paths: I)
+        where I: IntoIterator<Item = PathBuf> {
+        let mut all = self.paths.clone();
+        all.extend(paths);
+        self.set_paths(all);
+    }
+
+    /// Whether any of the files changed since the last call.
+    ///
+    /// A removed file counts as a change, so the error about it shows up, and it's picked up
+    /// again once it's recreated.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(event) = self.rx.try_recv() {
+            let path = match &event {
+                DebouncedEvent::Write(path)
+                | DebouncedEvent::Create(path)
+                | DebouncedEvent::Remove(path) => path,
+                // Atomic saves rename a temporary file over the real one, and some editors
+                // rename the real one away first.
+                DebouncedEvent::Rename(from, _) if self.paths.contains(from) => from,
+                DebouncedEvent::Rename(_, to) => to,
+                // Events were dropped, so anything could have changed.
+                DebouncedEvent::Rescan => {
+                    changed = true;
+                    continue;
+                }
+                DebouncedEvent::Error(e, path) => {
+                    eprintln!("File watcher error for {:?}: {}", path, e);
+                    continue;
+                }
+                _ => continue,
+            };
+            if self.paths.contains(path) {
+                eprintln!("Got file event: {:?}", event);
+                changed = true;
+            }
+        }
+        changed
+    }
+}
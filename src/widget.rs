@@ -0,0 +1,535 @@
+use std::convert::TryFrom;
+use std::f32;
+use std::slice;
+
+use imgui::{ImStr, ImString, Ui, im_str};
+use toml::Value as TomlValue;
+use toml::value::Table as TomlTable;
+
+use crate::StormUniform;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WidgetKind {
+    Slider,
+    Drag,
+    Input,
+    Checkbox,
+    Color,
+    Combo,
+}
+
+impl WidgetKind {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "slider" => Some(WidgetKind::Slider),
+            "drag" => Some(WidgetKind::Drag),
+            "input" => Some(WidgetKind::Input),
+            "checkbox" => Some(WidgetKind::Checkbox),
+            "color" => Some(WidgetKind::Color),
+            "combo" => Some(WidgetKind::Combo),
+            _ => None,
+        }
+    }
+}
+
+/// How a freeform uniform is shown in the "Shader Options" window, as set by an inline table in
+/// the shader's TOML block:
+///
+/// ```toml
+/// iterations = { widget = "slider", min = 1, max = 200, default = 50, tooltip = "Detail" }
+/// mode = { widget = "combo", items = ["Fill", "Outline"], label = "Draw mode" }
+/// ```
+///
+/// Missing fields fall back to a sensible widget for the uniform's type.
+#[derive(Clone, Debug, Default)]
+pub struct Widget {
+    pub kind: Option<WidgetKind>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub step: Option<f64>,
+    pub label: Option<String>,
+    pub tooltip: Option<String>,
+    /// Choices for a combo box, selected by index.
+    pub items: Vec<ImString>,
+}
+
+/// Float vector widgets, which only differ in the ImGui functions they call. They drag by
+/// default.
+macro_rules! float_vec_widget {
+    ($name:ident, $n:expr, $drag:ident, $slider:ident, $input:ident) => {
+        fn $name(&self, ui: &Ui, label: &ImStr, v: &mut [f32; $n]) -> bool {
+            let (min, max) = self.float_range();
+            let changed = match self.kind.unwrap_or(WidgetKind::Drag) {
+                WidgetKind::Slider => ui.$slider(label, v, min.unwrap_or(0.0), max.unwrap_or(1.0))
+                    .build(),
+                WidgetKind::Input => ui.$input(label, v)
+                    .build(),
+                _ => ui.$drag(label, v)
+                    .min(min.unwrap_or(f32::MIN))
+                    .max(max.unwrap_or(f32::MAX))
+                    .speed(self.step.map_or(0.01, |step| step as f32))
+                    .build(),
+            };
+            if changed {
+                clamp_all(v, min, max);
+            }
+            changed
+        }
+    };
+}
+
+/// Like `float_vec_widget`, for int vectors.
+macro_rules! int_vec_widget {
+    ($name:ident, $n:expr, $drag:ident, $slider:ident, $input:ident) => {
+        fn $name(&self, ui: &Ui, label: &ImStr, v: &mut [i32; $n]) -> bool {
+            let (min, max) = self.int_range();
+            let changed = match self.kind.unwrap_or(WidgetKind::Drag) {
+                WidgetKind::Slider => ui.$slider(label, v, min.unwrap_or(0), max.unwrap_or(500))
+                    .build(),
+                WidgetKind::Input => ui.$input(label, v)
+                    .build(),
+                _ => ui.$drag(label, v)
+                    .min(min.unwrap_or(i32::min_value()))
+                    .max(max.unwrap_or(i32::max_value()))
+                    .speed(self.step.map_or(1.0, |step| step as f32))
+                    .build(),
+            };
+            if changed {
+                clamp_all(v, min, max);
+            }
+            changed
+        }
+    };
+}
+
+impl Widget {
+    /// Parse a uniform's inline table, returning the widget and the `default` value, if any.
+    pub fn parse(table: &TomlTable) -> Result<(Self, Option<TomlValue>), String> {
+        let mut widget = Widget::default();
+        let mut default = None;
+        for (key, value) in table {
+            match key.as_str() {
+                "widget" => {
+                    let kind = value.as_str()
+                        .and_then(WidgetKind::parse)
+                        .ok_or("\"widget\" must be slider, drag, input, checkbox, color or combo")?;
+                    widget.kind = Some(kind);
+                }
+                "min" => widget.min = Some(parse_number(key, value)?),
+                "max" => widget.max = Some(parse_number(key, value)?),
+                "step" => widget.step = Some(parse_number(key, value)?),
+                "label" => widget.label = Some(parse_string(key, value)?),
+                "tooltip" => widget.tooltip = Some(parse_string(key, value)?),
+                "items" => {
+                    let items = value.as_array()
+                        .ok_or("\"items\" must be an array of strings")?;
+                    widget.items = items.iter()
+                        .map(|item| parse_string(key, item).map(ImString::new))
+                        .collect::<Result<_, _>>()?;
+                }
+                "default" => default = Some(value.clone()),
+                key => return Err(format!("Unknown widget setting \"{}\"", key)),
+            }
+        }
+        if widget.kind == Some(WidgetKind::Combo) && widget.items.is_empty() {
+            return Err("combo widgets need a list of \"items\"".into());
+        }
+        Ok((widget, default))
+    }
+
+    /// Draw the widget for a uniform, letting the user edit its value. It's labeled `text`
+    /// unless the widget has its own label.
+    pub fn draw(&self, ui: &Ui, name: &str, text: &str, value: &mut StormUniform) {
+        use StormUniform::*;
+
+        // Labels don't have to be unique, so the uniform's name is used as the ImGui ID.
+        let text = self.label.as_ref().map_or(text, String::as_str);
+        let label = ImString::new(format!("{}##{}", text, name));
+        let label: &ImStr = &label;
+
+        match value {
+            Float(f) => {
+                self.draw_float(ui, label, f);
+            }
+            FloatVec2(v) => {
+                self.draw_float2(ui, label, v);
+            }
+            FloatVec3(v) => {
+                self.draw_float3(ui, label, v);
+            }
+            FloatVec4(v) => {
+                self.draw_float4(ui, label, v);
+            }
+            // ImGui only edits single precision floats, so doubles go through a copy.
+            Double(d) => {
+                let mut f = *d as f32;
+                if self.draw_float(ui, label, &mut f) {
+                    *d = f as f64;
+                }
+            }
+            DoubleVec2(v) => {
+                let mut f = [v[0] as f32, v[1] as f32];
+                if self.draw_float2(ui, label, &mut f) {
+                    *v = [f[0] as f64, f[1] as f64];
+                }
+            }
+            DoubleVec3(v) => {
+                let mut f = [v[0] as f32, v[1] as f32, v[2] as f32];
+                if self.draw_float3(ui, label, &mut f) {
+                    *v = [f[0] as f64, f[1] as f64, f[2] as f64];
+                }
+            }
+            DoubleVec4(v) => {
+                let mut f = [v[0] as f32, v[1] as f32, v[2] as f32, v[3] as f32];
+                if self.draw_float4(ui, label, &mut f) {
+                    *v = [f[0] as f64, f[1] as f64, f[2] as f64, f[3] as f64];
+                }
+            }
+            Int(i) => {
+                self.draw_int(ui, label, i, WidgetKind::Slider);
+            }
+            IntVec2(v) => {
+                self.draw_int2(ui, label, v);
+            }
+            IntVec3(v) => {
+                self.draw_int3(ui, label, v);
+            }
+            IntVec4(v) => {
+                self.draw_int4(ui, label, v);
+            }
+            UnsignedInt(u) => self.draw_other_int(ui, text, name, slice::from_mut(u)),
+            UnsignedIntVec2(v) => self.draw_other_int(ui, text, name, v),
+            UnsignedIntVec3(v) => self.draw_other_int(ui, text, name, v),
+            UnsignedIntVec4(v) => self.draw_other_int(ui, text, name, v),
+            Int64(i) => self.draw_other_int(ui, text, name, slice::from_mut(i)),
+            Int64Vec2(v) => self.draw_other_int(ui, text, name, v),
+            Int64Vec3(v) => self.draw_other_int(ui, text, name, v),
+            Int64Vec4(v) => self.draw_other_int(ui, text, name, v),
+            UnsignedInt64(u) => self.draw_other_int(ui, text, name, slice::from_mut(u)),
+            UnsignedInt64Vec2(v) => self.draw_other_int(ui, text, name, v),
+            UnsignedInt64Vec3(v) => self.draw_other_int(ui, text, name, v),
+            UnsignedInt64Vec4(v) => self.draw_other_int(ui, text, name, v),
+            Bool(b) => {
+                ui.checkbox(label, b);
+            }
+            BoolVec2(v) => draw_bools(ui, text, name, v),
+            BoolVec3(v) => draw_bools(ui, text, name, v),
+            BoolVec4(v) => draw_bools(ui, text, name, v),
+            ColorRgb(c) => {
+                ui.color_edit(label, c)
+                    .build();
+            }
+            ColorRgba(c) => {
+                ui.color_edit(label, c)
+                    .build();
+            }
+            FloatMat2(m) => self.draw_columns(ui, text, name, m, Self::draw_float2),
+            FloatMat3(m) => self.draw_columns(ui, text, name, m, Self::draw_float3),
+            FloatMat4(m) => self.draw_columns(ui, text, name, m, Self::draw_float4),
+            // Kept up to date every frame, so there's nothing to edit.
+            Resolution(r) => {
+                ui.label_text(label, im_str!("{} x {}", r[0], r[1]));
+            }
+            Aspect(v) | Time(v) | TimeDelta(v) => {
+                ui.label_text(label, im_str!("{:.3}", v));
+            }
+            Frame(f) => {
+                ui.label_text(label, im_str!("{}", f));
+            }
+            Mouse(v) | MouseNormalized(v) | Date(v) => {
+                ui.label_text(label, im_str!("{:.3}, {:.3}, {:.3}, {:.3}", v[0], v[1], v[2], v[3]));
+            }
+        }
+
+        if let Some(tooltip) = &self.tooltip {
+            if ui.is_item_hovered() {
+                ui.tooltip_text(im_str!("{}", tooltip));
+            }
+        }
+    }
+
+    /// Clamp a value to the widget's range. Values are only clamped when they're edited, so
+    /// this is for the ones that aren't, like defaults from the TOML block.
+    pub fn clamp_value(&self, value: &mut StormUniform) {
+        use StormUniform::*;
+
+        let (float_min, float_max) = self.float_range();
+        let (int_min, int_max) = self.int_range();
+        match value {
+            Float(f) => clamp(f, float_min, float_max),
+            FloatVec2(v) => clamp_all(v, float_min, float_max),
+            FloatVec3(v) => clamp_all(v, float_min, float_max),
+            FloatVec4(v) => clamp_all(v, float_min, float_max),
+            Double(d) => clamp(d, self.min, self.max),
+            DoubleVec2(v) => clamp_all(v, self.min, self.max),
+            DoubleVec3(v) => clamp_all(v, self.min, self.max),
+            DoubleVec4(v) => clamp_all(v, self.min, self.max),
+            Int(i) => {
+                clamp(i, int_min, int_max);
+                if self.kind == Some(WidgetKind::Combo) {
+                    clamp(i, Some(0), Some(self.items.len() as i32 - 1));
+                }
+            }
+            IntVec2(v) => clamp_all(v, int_min, int_max),
+            IntVec3(v) => clamp_all(v, int_min, int_max),
+            IntVec4(v) => clamp_all(v, int_min, int_max),
+            FloatMat2(m) => m.iter_mut().for_each(|column| clamp_all(column, float_min, float_max)),
+            FloatMat3(m) => m.iter_mut().for_each(|column| clamp_all(column, float_min, float_max)),
+            FloatMat4(m) => m.iter_mut().for_each(|column| clamp_all(column, float_min, float_max)),
+            UnsignedInt(u) => self.clamp_wide(slice::from_mut(u)),
+            UnsignedIntVec2(v) => self.clamp_wide(v),
+            UnsignedIntVec3(v) => self.clamp_wide(v),
+            UnsignedIntVec4(v) => self.clamp_wide(v),
+            Int64(i) => self.clamp_wide(slice::from_mut(i)),
+            Int64Vec2(v) => self.clamp_wide(v),
+            Int64Vec3(v) => self.clamp_wide(v),
+            Int64Vec4(v) => self.clamp_wide(v),
+            UnsignedInt64(u) => self.clamp_wide(slice::from_mut(u)),
+            UnsignedInt64Vec2(v) => self.clamp_wide(v),
+            UnsignedInt64Vec3(v) => self.clamp_wide(v),
+            UnsignedInt64Vec4(v) => self.clamp_wide(v),
+            _ => {}
+        }
+    }
+
+    fn float_range(&self) -> (Option<f32>, Option<f32>) {
+        (self.min.map(|min| min as f32), self.max.map(|max| max as f32))
+    }
+
+    fn int_range(&self) -> (Option<i32>, Option<i32>) {
+        (self.min.map(|min| min as i32), self.max.map(|max| max as i32))
+    }
+
+    fn draw_float(&self, ui: &Ui, label: &ImStr, f: &mut f32) -> bool {
+        let (min, max) = self.float_range();
+        let changed = match self.kind.unwrap_or(WidgetKind::Slider) {
+            WidgetKind::Drag => ui.drag_float(label, f)
+                .min(min.unwrap_or(f32::MIN))
+                .max(max.unwrap_or(f32::MAX))
+                .speed(self.step.map_or(0.01, |step| step as f32))
+                .build(),
+            WidgetKind::Input => ui.input_float(label, f)
+                .step(self.step.map_or(0.0, |step| step as f32))
+                .build(),
+            _ => ui.slider_float(label, f, min.unwrap_or(0.0), max.unwrap_or(1.0))
+                .build(),
+        };
+        if changed {
+            clamp(f, min, max);
+        }
+        changed
+    }
+
+    fn draw_int(&self, ui: &Ui, label: &ImStr, i: &mut i32, default_kind: WidgetKind) -> bool {
+        let (min, max) = self.int_range();
+        let step = self.step.map_or(1, |step| step as i32);
+        let changed = match self.kind.unwrap_or(default_kind) {
+            WidgetKind::Drag => ui.drag_int(label, i)
+                .min(min.unwrap_or(i32::min_value()))
+                .max(max.unwrap_or(i32::max_value()))
+                .speed(step as f32)
+                .build(),
+            WidgetKind::Input => ui.input_int(label, i)
+                .step(step)
+                .build(),
+            WidgetKind::Checkbox => {
+                let mut checked = *i != 0;
+                let changed = ui.checkbox(label, &mut checked);
+                if changed {
+                    *i = checked as i32;
+                }
+                changed
+            }
+            WidgetKind::Combo => {
+                let items: Vec<&ImStr> = self.items.iter()
+                    .map(|item| &**item)
+                    .collect();
+                ui.combo(label, i, &items, items.len() as i32)
+            }
+            // Colors don't make sense for a single int, so it gets the default slider.
+            WidgetKind::Slider | WidgetKind::Color => ui.slider_int(label, i, min.unwrap_or(0), max.unwrap_or(500))
+                .build(),
+        };
+        if changed {
+            clamp(i, min, max);
+            if self.kind == Some(WidgetKind::Combo) {
+                clamp(i, Some(0), Some(self.items.len() as i32 - 1));
+            }
+        }
+        changed
+    }
+
+    float_vec_widget!(draw_float2, 2, drag_float2, slider_float2, input_float2);
+    float_vec_widget!(draw_float3, 3, drag_float3, slider_float3, input_float3);
+    float_vec_widget!(draw_float4, 4, drag_float4, slider_float4, input_float4);
+    int_vec_widget!(draw_int2, 2, drag_int2, slider_int2, input_int2);
+    int_vec_widget!(draw_int3, 3, drag_int3, slider_int3, input_int3);
+    int_vec_widget!(draw_int4, 4, drag_int4, slider_int4, input_int4);
+
+    /// Matrices are edited one column per row, since that's how GLSL lays them out.
+    fn draw_columns<C>(&self, ui: &Ui, text: &str, name: &str, columns: &mut [C], draw_column: fn(&Self, &Ui, &ImStr, &mut C) -> bool) {
+        let last = columns.len() - 1;
+        for (i, column) in columns.iter_mut().enumerate() {
+            let label = if i == last {
+                ImString::new(format!("{}##{}", text, name))
+            } else {
+                ImString::new(format!("##{}{}", name, i))
+            };
+            draw_column(self, ui, &label, column);
+        }
+    }
+
+    /// Unsigned and 64-bit integers don't fit in i32 inputs, so they're edited as text by
+    /// default, one input per component, and clamped to the widget's range and their type's.
+    /// Other kinds of widgets use the i32 ones, which `check` makes sure can hold the range.
+    fn draw_other_int<T>(&self, ui: &Ui, text: &str, name: &str, values: &mut [T])
+        where T: WideInt {
+        let (min, max) = self.wide_range::<T>();
+        let last = values.len() - 1;
+        for (i, value) in values.iter_mut().enumerate() {
+            // Only the last component shows the label, the others just need unique IDs.
+            let label = if i == last {
+                ImString::new(format!("{}##{}", text, name))
+            } else {
+                ImString::new(format!("##{}{}", name, i))
+            };
+            let wide: i128 = (*value).into();
+            let edited = match self.kind {
+                Some(kind) if kind != WidgetKind::Input => {
+                    let mut int = wide.max(i32::min_value() as i128).min(i32::max_value() as i128) as i32;
+                    if self.draw_int(ui, &label, &mut int, kind) {
+                        Some(int as i128)
+                    } else {
+                        None
+                    }
+                }
+                _ => {
+                    // Room for a sign and the 20 digits of the widest values.
+                    let mut buffer = ImString::with_capacity(21);
+                    buffer.push_str(&wide.to_string());
+                    let changed = ui.input_text(&label, &mut buffer)
+                        .chars_decimal(true)
+                        .build();
+                    // Half typed values like "-" are left alone until they parse.
+                    if changed {
+                        buffer.to_str().trim().parse::<i128>().ok()
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(new) = edited {
+                if let Ok(new) = T::try_from(new.max(min).min(max)) {
+                    *value = new;
+                }
+            }
+        }
+    }
+
+    /// The range of values for a `T` uniform, where `min` and `max` are within `T`'s own.
+    fn wide_range<T>(&self) -> (i128, i128)
+        where T: WideInt {
+        let (type_min, type_max): (i128, i128) = (T::MIN.into(), T::MAX.into());
+        let min = self.min.map_or(type_min, |min| (min.ceil() as i128).max(type_min).min(type_max));
+        let max = self.max.map_or(type_max, |max| (max.floor() as i128).max(type_min).min(type_max));
+        (min, max)
+    }
+
+    fn clamp_wide<T>(&self, values: &mut [T])
+        where T: WideInt {
+        let (min, max) = self.wide_range::<T>();
+        for value in values {
+            let wide: i128 = (*value).into();
+            if let Ok(clamped) = T::try_from(wide.max(min).min(max)) {
+                *value = clamped;
+            }
+        }
+    }
+
+    /// Check that the widget can edit `value`. Sliders and drags for unsigned and 64-bit
+    /// integers are ImGui's i32 ones, so they need a range that fits.
+    pub fn check(&self, value: &StormUniform) -> Result<(), String> {
+        use StormUniform::*;
+
+        let wide = match value {
+            UnsignedInt(_) | UnsignedIntVec2(_) | UnsignedIntVec3(_) | UnsignedIntVec4(_)
+            | Int64(_) | Int64Vec2(_) | Int64Vec3(_) | Int64Vec4(_)
+            | UnsignedInt64(_) | UnsignedInt64Vec2(_) | UnsignedInt64Vec3(_) | UnsignedInt64Vec4(_) => true,
+            _ => false,
+        };
+        let needs_range = match self.kind {
+            Some(WidgetKind::Slider) | Some(WidgetKind::Drag) | Some(WidgetKind::Color) => true,
+            _ => false,
+        };
+        let fits = |bound: Option<f64>| bound.map_or(false, |b| b >= i32::min_value() as f64 && b <= i32::max_value() as f64);
+        if wide && needs_range && !(fits(self.min) && fits(self.max)) {
+            return Err(format!("slider and drag widgets for a {} uniform need a min and max between {} and {}, use an input widget for larger values", value.type_name(), i32::min_value(), i32::max_value()));
+        }
+        Ok(())
+    }
+}
+
+/// Integer types too wide for i32 inputs.
+trait WideInt: Copy + Into<i128> + TryFrom<i128> {
+    const MIN: Self;
+    const MAX: Self;
+}
+
+impl WideInt for u32 {
+    const MIN: Self = 0;
+    const MAX: Self = u32::max_value();
+}
+
+impl WideInt for i64 {
+    const MIN: Self = i64::min_value();
+    const MAX: Self = i64::max_value();
+}
+
+impl WideInt for u64 {
+    const MIN: Self = 0;
+    const MAX: Self = u64::max_value();
+}
+
+/// A row of checkboxes for a bool vector, labeled once at the end.
+fn draw_bools(ui: &Ui, text: &str, name: &str, v: &mut [bool]) {
+    for (i, b) in v.iter_mut().enumerate() {
+        ui.checkbox(im_str!("##{}{}", name, i), b);
+        ui.same_line(0.0);
+    }
+    ui.text(text);
+}
+
+fn clamp<T>(value: &mut T, min: Option<T>, max: Option<T>)
+    where T: PartialOrd + Copy {
+    if let Some(min) = min {
+        if *value < min {
+            *value = min;
+        }
+    }
+    if let Some(max) = max {
+        if *value > max {
+            *value = max;
+        }
+    }
+}
+
+fn clamp_all<T>(values: &mut [T], min: Option<T>, max: Option<T>)
+    where T: PartialOrd + Copy {
+    for value in values {
+        clamp(value, min, max);
+    }
+}
+
+fn parse_number(key: &str, value: &TomlValue) -> Result<f64, String> {
+    match value {
+        TomlValue::Integer(i) => Ok(*i as f64),
+        TomlValue::Float(f) => Ok(*f),
+        _ => Err(format!("\"{}\" must be a number", key)),
+    }
+}
+
+fn parse_string(key: &str, value: &TomlValue) -> Result<String, String> {
+    value.as_str()
+        .map(String::from)
+        .ok_or_else(|| format!("\"{}\" must be a string", key))
+}